@@ -10,10 +10,24 @@ in
       default = withSystem ({ config, ... }: config.packages.default);
       defaultText = lib.literalMD "`packages.default` from the foo flake";
     };
-    botToken = mkOption {
-      type = types.str;
+    botTokenFile = mkOption {
+      type = types.nullOr types.str;
       default = null;
-      description = "Telegram Bot token for capturebot.";
+      example = "/run/secrets/capturebot-token";
+      description = ''
+        Absolute path, at runtime, of a file containing the Telegram Bot token
+        for capturebot. It is passed to the service with systemd's
+        LoadCredential, so it never enters the Nix store. Give it as a string
+        rather than a path literal, which would be copied into the store.
+      '';
+    };
+    environmentFile = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      description = ''
+        Absolute path, at runtime, of an environment file for the service,
+        which can set TELOXIDE_TOKEN instead of using botTokenFile.
+      '';
     };
     userId = mkOption {
       type = types.int;
@@ -28,6 +42,12 @@ in
   };
 
   config = mkIf cfg.enable {
+    assertions = [
+      {
+        assertion = cfg.botTokenFile != null || cfg.environmentFile != null;
+        message = "services.capturebot needs botTokenFile or an environmentFile setting TELOXIDE_TOKEN.";
+      }
+    ];
     systemd.services.capturebot = {
       name = "capturebot";
       wantedBy = "network-online.target";
@@ -36,11 +56,14 @@ in
         RestartSec = 3;
         Restart = "always";
         RestartSteps = 3;
+      } // optionalAttrs (cfg.botTokenFile != null) {
+        LoadCredential = "bot-token:${cfg.botTokenFile}";
+      } // optionalAttrs (cfg.environmentFile != null) {
+        EnvironmentFile = cfg.environmentFile;
       };
       environment = {
-        "CAPTUREBOT_USER_ID" = cfg.userId;
-        "CAPTUREBOT_SAVE_DIR" = cfg.SaveDir;
-        "TELOXIDE_TOKEN" = cfg.botToken;
+        "CAPTUREBOT_USER_ID" = toString cfg.userId;
+        "CAPTUREBOT_SAVE_DIR" = cfg.saveDir;
       };
     };
   };
@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use teloxide::types::User;
+use tokio::fs;
+
+use crate::write::replace_file;
+
+// how often a stranger who keeps writing shows up in the log again
+const LOG_INTERVAL: TimeDelta = TimeDelta::hours(1);
+const SNIPPET_LENGTH: usize = 100;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessStatus {
+    /// The owner hasn't decided yet.
+    #[default]
+    Pending,
+    Allowed,
+    Blocked,
+}
+
+/// Someone who messaged the bot without being configured as a user.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Stranger {
+    pub user_id: u64,
+    pub username: Option<String>,
+    pub name: String,
+    pub first_message: String,
+    pub count: u64,
+    pub first_seen: DateTime<Utc>,
+    pub last_logged: DateTime<Utc>,
+    pub status: AccessStatus,
+}
+
+/// What to do about a message from a stranger.
+#[derive(Debug, PartialEq)]
+pub enum Sighting {
+    /// First contact, so the owner should hear about it.
+    New,
+    /// Someone already known, due for another log line.
+    Logged,
+    /// Someone already known who was logged recently.
+    Quiet,
+}
+
+/// Everyone who has messaged the bot without being a configured user, and
+/// what the owner decided about them. Kept in `state_dir` across restarts.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct AccessLog {
+    strangers: HashMap<u64, Stranger>,
+}
+
+impl AccessLog {
+    /// Reads the access log at `path`. A missing log just means nobody has
+    /// turned up yet; an unreadable one is logged and started over.
+    pub fn load(path: &Path) -> Self {
+        match std::fs::read_to_string(path) {
+            Ok(s) => serde_json::from_str(&s)
+                .inspect_err(|e| log::warn!("discarding unreadable access log: {e}"))
+                .unwrap_or_default(),
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    log::warn!("couldn't read access log {}: {e}", path.display());
+                }
+                Self::default()
+            }
+        }
+    }
+
+    pub async fn save(&self, path: &Path) -> Result<(), std::io::Error> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        replace_file(path, &serde_json::to_string_pretty(self)?).await
+    }
+
+    /// Counts a message from `user`, remembering the start of the first one.
+    pub fn record(&mut self, user: &User, text: &str, now: DateTime<Utc>) -> Sighting {
+        match self.strangers.get_mut(&user.id.0) {
+            Some(stranger) => {
+                stranger.count += 1;
+                if now - stranger.last_logged < LOG_INTERVAL {
+                    return Sighting::Quiet;
+                }
+                stranger.last_logged = now;
+                Sighting::Logged
+            }
+            None => {
+                self.strangers.insert(
+                    user.id.0,
+                    Stranger {
+                        user_id: user.id.0,
+                        username: user.username.clone(),
+                        name: user.full_name(),
+                        first_message: text.chars().take(SNIPPET_LENGTH).collect(),
+                        count: 1,
+                        first_seen: now,
+                        last_logged: now,
+                        status: AccessStatus::Pending,
+                    },
+                );
+                Sighting::New
+            }
+        }
+    }
+
+    pub fn get(&self, user_id: u64) -> Option<&Stranger> {
+        self.strangers.get(&user_id)
+    }
+
+    /// Records the owner's decision about `user_id`. Returns false for
+    /// someone who never wrote in.
+    pub fn decide(&mut self, user_id: u64, status: AccessStatus) -> bool {
+        self.strangers
+            .get_mut(&user_id)
+            .map(|stranger| stranger.status = status)
+            .is_some()
+    }
+
+    pub fn is_blocked(&self, user_id: u64) -> bool {
+        self.get(user_id)
+            .is_some_and(|s| s.status == AccessStatus::Blocked)
+    }
+
+    pub fn allowed(&self) -> impl Iterator<Item = u64> + '_ {
+        self.strangers
+            .values()
+            .filter(|s| s.status == AccessStatus::Allowed)
+            .map(|s| s.user_id)
+    }
+}
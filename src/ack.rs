@@ -0,0 +1,166 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::write::replace_file;
+
+// how many captures back /undo can go
+const MAX_RECENT: usize = 50;
+/// How many acknowledgements are remembered; the buttons and replies of
+/// older ones stop working.
+pub const MAX_ACKS: usize = 500;
+
+/// A question the bot asked to follow up on a button under an
+/// acknowledgement, whose answer is a reply to it.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Prompt {
+    /// Which tags to give the note.
+    Tag,
+    /// Which note to link it to.
+    Link,
+}
+
+/// The note the bot acknowledged capturing with one of its messages, or
+/// asked a question about.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Ack {
+    /// Whose notes the note is in.
+    pub user_id: u64,
+    pub capturebot_id: String,
+    /// What was done to the note from the acknowledgement's buttons, for
+    /// its text.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub done: Vec<String>,
+    /// For questions, what's being asked and the message ID of the
+    /// acknowledgement it follows up on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<(Prompt, i32)>,
+}
+
+impl Ack {
+    pub fn new(user_id: u64, capturebot_id: String) -> Self {
+        Self {
+            user_id,
+            capturebot_id,
+            done: Vec::new(),
+            prompt: None,
+        }
+    }
+}
+
+/// Which note each acknowledgement the bot sent is about, so that replying
+/// to an acknowledgement can pick out its note. Kept in `state_dir` across
+/// restarts.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Acks {
+    /// By `CHAT_ID:MESSAGE_ID` of the acknowledgement, since message IDs
+    /// only count up within a chat.
+    acks: HashMap<String, Ack>,
+    /// The keys of `acks`, oldest first, for dropping the oldest once
+    /// there are too many.
+    #[serde(default)]
+    order: VecDeque<String>,
+    /// Each user's latest captures by capturebot ID, most recent last, for
+    /// `/undo`.
+    #[serde(default)]
+    recent: HashMap<u64, Vec<String>>,
+}
+
+fn key(chat_id: i64, message_id: i32) -> String {
+    format!("{chat_id}:{message_id}")
+}
+
+impl Acks {
+    /// Reads the acknowledgements at `path`. Missing ones just mean nothing
+    /// was acknowledged yet; unreadable ones are logged and started over.
+    pub fn load(path: &Path) -> Self {
+        match std::fs::read_to_string(path) {
+            Ok(s) => {
+                let mut acks: Self = serde_json::from_str(&s)
+                    .inspect_err(|e| log::warn!("discarding unreadable acknowledgements: {e}"))
+                    .unwrap_or_default();
+                // files from before `order` was kept have all their
+                // acknowledgements count as the oldest
+                let unordered: Vec<String> = acks
+                    .acks
+                    .keys()
+                    .filter(|k| !acks.order.contains(k))
+                    .cloned()
+                    .collect();
+                for key in unordered.into_iter().rev() {
+                    acks.order.push_front(key);
+                }
+                acks
+            }
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    log::warn!("couldn't read acknowledgements {}: {e}", path.display());
+                }
+                Self::default()
+            }
+        }
+    }
+
+    pub async fn save(&self, path: &Path) -> Result<(), std::io::Error> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        replace_file(path, &serde_json::to_string(self)?).await
+    }
+
+    /// Remembers that message `message_id` in `chat_id` acknowledged `ack`.
+    pub fn insert(&mut self, chat_id: i64, message_id: i32, ack: Ack) {
+        let key = key(chat_id, message_id);
+        self.order.retain(|k| *k != key);
+        self.order.push_back(key.clone());
+        self.acks.insert(key, ack);
+        while self.order.len() > MAX_ACKS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.acks.remove(&oldest);
+            }
+        }
+    }
+
+    /// The note message `message_id` in `chat_id` acknowledged, if it was
+    /// an acknowledgement.
+    pub fn get(&self, chat_id: i64, message_id: i32) -> Option<&Ack> {
+        self.acks.get(&key(chat_id, message_id))
+    }
+
+    pub fn get_mut(&mut self, chat_id: i64, message_id: i32) -> Option<&mut Ack> {
+        self.acks.get_mut(&key(chat_id, message_id))
+    }
+
+    pub fn remove(&mut self, chat_id: i64, message_id: i32) -> Option<Ack> {
+        let key = key(chat_id, message_id);
+        self.order.retain(|k| *k != key);
+        self.acks.remove(&key)
+    }
+
+    /// Remembers `capturebot_id` as `user_id`'s latest capture.
+    pub fn record_capture(&mut self, user_id: u64, capturebot_id: String) {
+        let recent = self.recent.entry(user_id).or_default();
+        recent.push(capturebot_id);
+        if recent.len() > MAX_RECENT {
+            recent.remove(0);
+        }
+    }
+
+    pub fn latest_capture(&self, user_id: u64) -> Option<&str> {
+        self.recent.get(&user_id)?.last().map(String::as_str)
+    }
+
+    /// Forgets a note that was deleted, along with what acknowledged it.
+    pub fn forget_capture(&mut self, user_id: u64, capturebot_id: &str) {
+        if let Some(recent) = self.recent.get_mut(&user_id) {
+            recent.retain(|id| id != capturebot_id);
+        }
+        self.acks
+            .retain(|_, ack| !(ack.user_id == user_id && ack.capturebot_id == capturebot_id));
+        let acks = &self.acks;
+        self.order.retain(|k| acks.contains_key(k));
+    }
+}
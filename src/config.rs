@@ -1,4 +1,86 @@
-use std::{env, path::PathBuf};
+use std::fmt::{self, Display};
+use std::path::{Path, PathBuf};
+use std::{env, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::access::AccessLog;
+use crate::denote;
+use crate::path_template::{PathContext, PathTemplate};
+use crate::slug::{self, SlugStyle};
+use crate::timezone::Timezone;
+
+/// How new notes are named and laid out on disk.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum NoteFormat {
+    /// A top-level property drawer with `:ID:`, named by `path_template`.
+    #[default]
+    OrgRoam,
+    /// Denote's front matter and `IDENTIFIER--slug__tags.org` file names.
+    Denote,
+}
+
+/// Where new captures end up.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum CaptureTarget {
+    /// A new file per capture, named by `note_format`.
+    #[default]
+    Files,
+    /// A level-1 heading per capture, appended to this file.
+    Inbox(PathBuf),
+    /// A heading per capture in the day's `YYYY-MM-DD.org` file in this
+    /// directory, org-roam dailies style.
+    Dailies(PathBuf),
+}
+
+impl FromStr for NoteFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "org-roam" | "orgroam" => Ok(Self::OrgRoam),
+            "denote" => Ok(Self::Denote),
+            other => Err(format!(
+                "unknown note format {other:?}, expected org-roam or denote"
+            )),
+        }
+    }
+}
+
+impl Display for NoteFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::OrgRoam => "org-roam",
+            Self::Denote => "denote",
+        })
+    }
+}
+
+/// The name of the systemd credential (`LoadCredential=bot-token:...`) the
+/// bot token is read from.
+static TOKEN_CREDENTIAL: &str = "bot-token";
+
+/// The Telegram bot token, which neither `Debug` nor `Display` will show.
+#[derive(Clone, PartialEq)]
+pub struct BotToken(String);
+
+impl BotToken {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for BotToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("BotToken(<redacted>)")
+    }
+}
+
+impl Display for BotToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
 
 #[derive(Clone)]
 pub struct CapturebotConfig {
@@ -6,26 +88,698 @@ pub struct CapturebotConfig {
     pub read_dir: PathBuf,
     pub save_dir: PathBuf,
     pub backup_json: Option<PathBuf>,
+    pub tolerant_load: bool,
+    pub report_load_errors: bool,
+    pub index_cache: Option<PathBuf>,
+    /// The directory of the full-text search index, kept up to date as notes
+    /// are captured and loaded. Without one, searches scan every note. Like
+    /// the note directories, every user needs their own.
+    pub fulltext_index: Option<PathBuf>,
+    /// Whether the pages captured links point to are fetched and their text
+    /// added to the full-text index.
+    pub index_articles: bool,
+    pub path_template: PathTemplate,
+    pub note_format: NoteFormat,
+    pub slug_style: SlugStyle,
+    pub capture_target: CaptureTarget,
+    pub timezone: Timezone,
+    pub bot_token: Option<BotToken>,
+    /// Where the bot keeps what it needs to remember that isn't notes, like
+    /// who it has let in.
+    pub state_dir: PathBuf,
+    /// Where deleted notes are moved to. It mustn't be under `save_dir` or
+    /// `read_dir`, where they'd be loaded again.
+    pub trash_dir: PathBuf,
+    /// Everyone else allowed to capture, each with their own directories
+    /// and notes.
+    pub users: Vec<CapturebotConfig>,
+    /// Group chats and channels the bot captures from.
+    pub chats: Vec<ChatConfig>,
+    /// The bot's own username, for recognizing mentions of it in groups.
+    /// Asked from Telegram at startup unless it's configured.
+    pub bot_username: Option<String>,
+    /// Tags every note captured with these settings gets, like those of
+    /// the forum topic it was posted in. Never set in the config file.
+    pub capture_tags: Vec<String>,
+}
+
+/// A group chat the bot captures from, when it's mentioned, sent
+/// `/capture`, or replied to a `/capture` in, or a channel it captures every
+/// post of.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChatConfig {
+    pub chat_id: i64,
+    /// Whose notes captures from the chat go into.
+    pub user_id: u64,
+    /// Where in that user's notes they go.
+    pub save_dir: PathBuf,
+    /// Where captures from the chat's forum topics go, for forum-enabled
+    /// supergroups.
+    pub topics: Vec<TopicConfig>,
+}
+
+/// A forum topic, picked out by thread ID, name, or both, and where
+/// captures from it go: a directory of its own under the chat's, a tag, or
+/// both. A topic configured with only a tag stays in the chat's directory,
+/// and topics that aren't configured at all get a directory named after
+/// them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TopicConfig {
+    pub thread_id: Option<i32>,
+    pub name: Option<String>,
+    pub save_dir: Option<PathBuf>,
+    pub tag: Option<String>,
+}
+
+impl ChatConfig {
+    /// The configured topic with this thread ID, or failing that, with this
+    /// name.
+    pub fn topic(&self, thread_id: i32, name: Option<&str>) -> Option<&TopicConfig> {
+        self.topics
+            .iter()
+            .find(|t| t.thread_id == Some(thread_id))
+            .or_else(|| {
+                let name = name?;
+                self.topics
+                    .iter()
+                    .find(|t| t.name.as_deref().is_some_and(|n| n.eq_ignore_ascii_case(name)))
+            })
+    }
+}
+
+/// A `[[chats]]` table in the config file. `save_dir` is relative to the
+/// user's own, which it defaults to.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct ChatFile {
+    chat_id: i64,
+    user_id: Option<u64>,
+    save_dir: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    topics: Vec<TopicFile>,
+}
+
+/// A `[[chats.topics]]` table. `save_dir` is relative to the chat's.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct TopicFile {
+    thread_id: Option<i32>,
+    name: Option<String>,
+    save_dir: Option<PathBuf>,
+    tag: Option<String>,
+}
+
+/// The settings as written in the config file. Everything is optional here
+/// so that environment variables can fill in or override any of it.
+///
+/// Each `[[users]]` table takes the same settings for another person, except
+/// for the bot token and backup location. Unset note settings are taken from
+/// the top level, but directories never are.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    user_id: Option<u64>,
+    save_dir: Option<PathBuf>,
+    read_dir: Option<PathBuf>,
+    backup_json: Option<PathBuf>,
+    tolerant_load: Option<bool>,
+    report_load_errors: Option<bool>,
+    index_cache: Option<PathBuf>,
+    fulltext_index: Option<PathBuf>,
+    index_articles: Option<bool>,
+    path_template: Option<String>,
+    note_format: Option<String>,
+    slug_style: Option<String>,
+    inbox: Option<PathBuf>,
+    dailies: Option<PathBuf>,
+    timezone: Option<String>,
+    bot_token: Option<String>,
+    bot_token_file: Option<PathBuf>,
+    state_dir: Option<PathBuf>,
+    trash_dir: Option<PathBuf>,
+    bot_username: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    users: Vec<ConfigFile>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    chats: Vec<ChatFile>,
+}
+
+/// Everything that's wrong with a configuration, so it can all be fixed in
+/// one go.
+#[derive(Debug)]
+pub struct ConfigError(pub(crate) Vec<String>);
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid configuration:")?;
+        for problem in &self.0 {
+            write!(f, "\n  - {problem}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn env_flag(value: &str) -> bool {
+    matches!(value.to_lowercase().as_str(), "1" | "true" | "yes")
+}
+
+/// Takes `--config <path>` out of the command line arguments, falling back to
+/// `CAPTUREBOT_CONFIG` when it isn't given.
+pub fn take_config_arg(args: &mut Vec<String>) -> Option<PathBuf> {
+    if let Some(i) = args.iter().position(|a| a == "--config") {
+        let path = args.get(i + 1).cloned();
+        args.drain(i..(i + 2).min(args.len()));
+        return path.map(PathBuf::from);
+    }
+    if let Some(i) = args.iter().position(|a| a.starts_with("--config=")) {
+        let arg = args.remove(i);
+        return Some(PathBuf::from(&arg["--config=".len()..]));
+    }
+    env::var("CAPTUREBOT_CONFIG").ok().map(PathBuf::from)
+}
+
+impl ConfigFile {
+    fn read(path: &Path) -> Result<Self, ConfigError> {
+        let source = std::fs::read_to_string(path).map_err(|e| {
+            ConfigError(vec![format!("couldn't read {}: {e}", path.display())])
+        })?;
+        toml::from_str(&source)
+            .map_err(|e| ConfigError(vec![format!("couldn't parse {}: {e}", path.display())]))
+    }
+
+    /// Replaces settings with the `CAPTUREBOT_*` environment variables that
+    /// are set.
+    fn override_from_env(&mut self, problems: &mut Vec<String>) {
+        let var = |name: &str| env::var(name).ok();
+        if let Some(id) = var("CAPTUREBOT_USER_ID") {
+            match id.parse() {
+                Ok(id) => self.user_id = Some(id),
+                Err(_) => problems.push(format!("CAPTUREBOT_USER_ID {id:?} should be an integer")),
+            }
+        }
+        let paths = [
+            ("CAPTUREBOT_SAVE_DIR", &mut self.save_dir),
+            ("CAPTUREBOT_READ_DIR", &mut self.read_dir),
+            ("CAPTUREBOT_BACKUP_LOCATION", &mut self.backup_json),
+            ("CAPTUREBOT_INDEX_CACHE", &mut self.index_cache),
+            ("CAPTUREBOT_FULLTEXT_INDEX", &mut self.fulltext_index),
+            ("CAPTUREBOT_INBOX", &mut self.inbox),
+            ("CAPTUREBOT_DAILIES", &mut self.dailies),
+            ("CAPTUREBOT_TOKEN_FILE", &mut self.bot_token_file),
+            ("CAPTUREBOT_STATE_DIR", &mut self.state_dir),
+            ("CAPTUREBOT_TRASH_DIR", &mut self.trash_dir),
+        ];
+        for (name, setting) in paths {
+            if let Some(path) = var(name) {
+                *setting = Some(PathBuf::from(path));
+            }
+        }
+        let strings = [
+            ("CAPTUREBOT_PATH_TEMPLATE", &mut self.path_template),
+            ("CAPTUREBOT_NOTE_FORMAT", &mut self.note_format),
+            ("CAPTUREBOT_SLUG_STYLE", &mut self.slug_style),
+            ("CAPTUREBOT_TIMEZONE", &mut self.timezone),
+        ];
+        for (name, setting) in strings {
+            if let Some(value) = var(name) {
+                *setting = Some(value);
+            }
+        }
+        let flags = [
+            ("CAPTUREBOT_TOLERANT_LOAD", &mut self.tolerant_load),
+            ("CAPTUREBOT_REPORT_LOAD_ERRORS", &mut self.report_load_errors),
+            ("CAPTUREBOT_INDEX_ARTICLES", &mut self.index_articles),
+        ];
+        for (name, setting) in flags {
+            if let Some(value) = var(name) {
+                *setting = Some(env_flag(&value));
+            }
+        }
+    }
+}
+
+// parses an optional setting, noting what's wrong with it if it doesn't
+fn parse_setting<T: FromStr + Default>(value: Option<String>, problems: &mut Vec<String>) -> T
+where
+    T::Err: Display,
+{
+    value.map_or(T::default(), |v| {
+        v.parse().unwrap_or_else(|e: T::Err| {
+            problems.push(e.to_string());
+            T::default()
+        })
+    })
+}
+
+fn read_token(path: &Path, problems: &mut Vec<String>) -> Option<BotToken> {
+    match std::fs::read_to_string(path) {
+        Ok(token) => Some(BotToken(token.trim().to_string())),
+        Err(e) => {
+            problems.push(format!("couldn't read the bot token from {}: {e}", path.display()));
+            None
+        }
+    }
+}
+
+/// The bot token from, in order, `bot_token_file`, the systemd credentials
+/// directory, `bot_token` in the config file, or `TELOXIDE_TOKEN`.
+fn resolve_token(file: &ConfigFile, problems: &mut Vec<String>) -> Option<BotToken> {
+    if let Some(path) = &file.bot_token_file {
+        return read_token(path, problems);
+    }
+    if let Ok(dir) = env::var("CREDENTIALS_DIRECTORY") {
+        let path = Path::new(&dir).join(TOKEN_CREDENTIAL);
+        if path.exists() {
+            return read_token(&path, problems);
+        }
+    }
+    file.bot_token
+        .clone()
+        .or(env::var("TELOXIDE_TOKEN").ok())
+        .map(BotToken)
+}
+
+/// `path` made absolute, with symlinks resolved as far as it exists, so that
+/// paths written differently can be compared.
+fn normalized(path: &Path) -> PathBuf {
+    let absolute = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let mut existing = absolute.as_path();
+    let mut rest = Vec::new();
+    loop {
+        if let Ok(canonical) = existing.canonicalize() {
+            return canonical.join(rest.into_iter().rev().collect::<PathBuf>());
+        }
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                rest.push(name);
+                existing = parent;
+            }
+            _ => return absolute,
+        }
+    }
+}
+
+fn check_dir(name: &str, dir: &Path, writable: bool, problems: &mut Vec<String>) {
+    if !dir.is_dir() {
+        problems.push(format!("{name} {} doesn't exist or isn't a directory", dir.display()));
+        return;
+    }
+    if writable {
+        let probe = dir.join(format!(".capturebot-{}.tmp", uuidgen::gen_uuid(true)));
+        match std::fs::File::create(&probe) {
+            Ok(_) => {
+                std::fs::remove_file(&probe).ok();
+            }
+            Err(e) => problems.push(format!("{name} {} isn't writable: {e}", dir.display())),
+        }
+    }
 }
 
 impl CapturebotConfig {
-    pub fn from_env() -> Self {
-	let save_dir = PathBuf::from(
-                env::var("CAPTUREBOT_SAVE_DIR").unwrap_or_else(|_| "./out/".to_string()),
-        );
-	let r = Self {
-            user_id: env::var("CAPTUREBOT_USER_ID")
-                .expect("Specify user ID")
-                .parse::<u64>()
-                .expect("User ID should be an integer"),
-	    read_dir: env::var("CAPTUREBOT_READ_DIR").map_or(save_dir.clone(), PathBuf::from),
+    /// Reads the config file at `path`, if there is one, applies the
+    /// environment variable overrides and checks the result.
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let mut file = match path {
+            Some(path) => ConfigFile::read(path)?,
+            None => ConfigFile::default(),
+        };
+        let mut problems = Vec::new();
+        file.override_from_env(&mut problems);
+        let bot_token = resolve_token(&file, &mut problems);
+        let users = std::mem::take(&mut file.users);
+        let chats = std::mem::take(&mut file.chats);
+        let mut config = Self::resolve(file.clone(), file.state_dir.clone(), &mut problems);
+        config.bot_token = bot_token;
+        config.check_dirs(&mut problems);
+        for (i, user) in users.into_iter().enumerate() {
+            let mut user_problems = Vec::new();
+            let user = Self::resolve_user(user, &file, &mut user_problems);
+            if user_problems.is_empty() {
+                user.check_dirs(&mut user_problems);
+            }
+            problems.extend(user_problems.into_iter().map(|p| format!("users[{i}]: {p}")));
+            config.users.push(user);
+        }
+        for user_id in AccessLog::load(&config.access_log_path()).allowed() {
+            if config.for_user(user_id).is_none() {
+                let guest = config.guest(user_id);
+                config.users.push(guest);
+            }
+        }
+        config.check_users(&mut problems);
+        for chat in chats {
+            let user_id = chat.user_id.unwrap_or(config.user_id);
+            let Some(user) = config.for_user(user_id) else {
+                problems.push(format!("chat {}: {user_id} isn't a user", chat.chat_id));
+                continue;
+            };
+            let save_dir = user.save_dir.join(chat.save_dir.unwrap_or_default());
+            let mut topics = Vec::new();
+            for topic in chat.topics {
+                if topic.thread_id.is_none() && topic.name.is_none() {
+                    problems.push(format!(
+                        "chat {}: topics need a thread_id or a name",
+                        chat.chat_id
+                    ));
+                }
+                topics.push(TopicConfig {
+                    thread_id: topic.thread_id,
+                    name: topic.name,
+                    save_dir: topic.save_dir.map(|dir| save_dir.join(dir)),
+                    tag: topic.tag,
+                });
+            }
+            config.chats.push(ChatConfig {
+                chat_id: chat.chat_id,
+                user_id,
+                save_dir,
+                topics,
+            });
+        }
+        if problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError(problems))
+        }
+    }
+
+    /// Resolves settings as written in the config file. `state_dir` is the
+    /// top level's, which users share.
+    fn resolve(file: ConfigFile, state_dir: Option<PathBuf>, problems: &mut Vec<String>) -> Self {
+        let save_dir = file.save_dir.unwrap_or(PathBuf::from("./out/"));
+        let capture_target = match (file.inbox, file.dailies) {
+            (Some(_), Some(_)) => {
+                problems.push("set only one of inbox and dailies".to_string());
+                CaptureTarget::Files
+            }
+            (Some(inbox), None) => CaptureTarget::Inbox(save_dir.join(inbox)),
+            (None, Some(dailies)) => CaptureTarget::Dailies(save_dir.join(dailies)),
+            (None, None) => CaptureTarget::Files,
+        };
+        let path_template = parse_setting(file.path_template, problems);
+        let note_format = parse_setting(file.note_format, problems);
+        let slug_style = parse_setting(file.slug_style, problems);
+        let timezone = parse_setting(file.timezone, problems);
+        let user_id = file.user_id.unwrap_or_else(|| {
+            problems.push("user_id is required (or set CAPTUREBOT_USER_ID)".to_string());
+            0
+        });
+        let state_dir = state_dir.unwrap_or(PathBuf::from("./state/"));
+        Self {
+            user_id,
+            read_dir: file.read_dir.unwrap_or(save_dir.clone()),
             save_dir,
-            backup_json: env::var("CAPTUREBOT_BACKUP_LOCATION")
-                .ok()
-                .map(PathBuf::from),
+            backup_json: file.backup_json,
+            tolerant_load: file.tolerant_load.unwrap_or_default(),
+            report_load_errors: file.report_load_errors.unwrap_or_default(),
+            index_cache: file.index_cache,
+            fulltext_index: file.fulltext_index,
+            index_articles: file.index_articles.unwrap_or_default(),
+            path_template,
+            note_format,
+            slug_style,
+            capture_target,
+            timezone,
+            bot_token: None,
+            trash_dir: file
+                .trash_dir
+                .unwrap_or(state_dir.join("trash").join(user_id.to_string())),
+            state_dir,
+            users: Vec::new(),
+            chats: Vec::new(),
+            bot_username: file.bot_username,
+            capture_tags: Vec::new(),
+        }
+    }
+
+    /// Settings for someone the owner let in from the bot rather than the
+    /// config file: the owner's note settings, with notes of their own under
+    /// `state_dir`.
+    pub fn guest(&self, user_id: u64) -> Self {
+        let save_dir = self.guest_dir(user_id);
+        Self {
+            user_id,
+            read_dir: save_dir.clone(),
+            trash_dir: self.state_dir.join("trash").join(user_id.to_string()),
+            backup_json: None,
+            report_load_errors: false,
+            index_cache: None,
+            fulltext_index: None,
+            bot_token: None,
+            users: Vec::new(),
+            chats: Vec::new(),
+            ..self.saving_to(save_dir)
+        }
+    }
+
+    /// These settings, but saving new notes under `save_dir`, with the inbox
+    /// or dailies moved along.
+    pub fn saving_to(&self, save_dir: PathBuf) -> Self {
+        let rebase = |path: &Path| save_dir.join(path.strip_prefix(&self.save_dir).unwrap_or(path));
+        Self {
+            capture_target: match &self.capture_target {
+                CaptureTarget::Files => CaptureTarget::Files,
+                CaptureTarget::Inbox(path) => CaptureTarget::Inbox(rebase(path)),
+                CaptureTarget::Dailies(path) => CaptureTarget::Dailies(rebase(path)),
+            },
+            save_dir,
+            ..self.clone()
+        }
+    }
+
+    /// The group chat `chat_id`, if captures from it are wanted.
+    pub fn chat(&self, chat_id: i64) -> Option<&ChatConfig> {
+        self.chats.iter().find(|c| c.chat_id == chat_id)
+    }
+
+    /// The settings captures from the group chat `chat_id` are saved with:
+    /// those of the user the chat belongs to, saving into the chat's
+    /// directory.
+    pub fn for_chat(&self, chat_id: i64) -> Option<Self> {
+        let chat = self.chat(chat_id)?;
+        Some(self.for_user(chat.user_id)?.saving_to(chat.save_dir.clone()))
+    }
+
+    /// The settings captures from a forum topic of the group chat `chat_id`
+    /// are saved with: like the chat's, but saving into the topic's
+    /// directory and tagging notes with its tag. `name` is the topic's name,
+    /// when it's known.
+    pub fn for_topic(&self, chat_id: i64, thread_id: i32, name: Option<&str>) -> Option<Self> {
+        let chat = self.chat(chat_id)?;
+        let user = self.for_user(chat.user_id)?;
+        let (save_dir, tag) = match chat.topic(thread_id, name) {
+            Some(topic) => (
+                topic.save_dir.clone().unwrap_or(chat.save_dir.clone()),
+                topic.tag.clone(),
+            ),
+            None => (
+                name.map_or(chat.save_dir.clone(), |name| {
+                    chat.save_dir.join(slug::slug(name, user.slug_style))
+                }),
+                None,
+            ),
         };
-	println!("{:?} {:?} {:?}", r.user_id, r.save_dir, r.backup_json);
-	r
+        let mut config = user.saving_to(save_dir);
+        config.capture_tags.extend(tag);
+        Some(config)
+    }
+
+    pub fn guest_dir(&self, user_id: u64) -> PathBuf {
+        self.state_dir.join("guests").join(user_id.to_string())
+    }
+
+    pub fn access_log_path(&self) -> PathBuf {
+        self.state_dir.join("access.json")
+    }
+
+    pub fn acks_path(&self) -> PathBuf {
+        self.state_dir.join("acks.json")
+    }
+
+    /// Resolves a `[[users]]` entry, filling in note settings it leaves out
+    /// from the top level.
+    fn resolve_user(mut user: ConfigFile, top: &ConfigFile, problems: &mut Vec<String>) -> Self {
+        if user.bot_token.is_some() || user.bot_token_file.is_some() {
+            problems.push("only the top level can set the bot token".to_string());
+        }
+        if user.backup_json.is_some()
+            || user.state_dir.is_some()
+            || user.bot_username.is_some()
+            || !user.users.is_empty()
+            || !user.chats.is_empty()
+        {
+            problems.push(
+                "backup_json, state_dir, bot_username, users and chats can only be set at the \
+                 top level"
+                    .to_string(),
+            );
+        }
+        if user.save_dir.is_none() {
+            problems.push("save_dir is required".to_string());
+        }
+        user.tolerant_load = user.tolerant_load.or(top.tolerant_load);
+        user.report_load_errors = user.report_load_errors.or(top.report_load_errors);
+        user.index_articles = user.index_articles.or(top.index_articles);
+        user.path_template = user.path_template.or(top.path_template.clone());
+        user.note_format = user.note_format.or(top.note_format.clone());
+        user.slug_style = user.slug_style.or(top.slug_style.clone());
+        user.timezone = user.timezone.or(top.timezone.clone());
+        if user.inbox.is_none() && user.dailies.is_none() {
+            user.inbox = top.inbox.clone();
+            user.dailies = top.dailies.clone();
+        }
+        Self::resolve(user, top.state_dir.clone(), problems)
+    }
+
+    // keeps users from sharing an identity or a place to write notes
+    fn check_users(&self, problems: &mut Vec<String>) {
+        let users: Vec<&Self> = self.all_users().collect();
+        for (i, user) in users.iter().enumerate() {
+            for other in &users[..i] {
+                if user.user_id == other.user_id {
+                    problems.push(format!("user {} is configured more than once", user.user_id));
+                } else if user.save_dir == other.save_dir {
+                    problems.push(format!(
+                        "users {} and {} share save_dir {}",
+                        other.user_id,
+                        user.user_id,
+                        user.save_dir.display()
+                    ));
+                }
+                // each index only holds one user's notes, and drops the rest
+                let index = user.fulltext_index.as_deref().map(normalized);
+                let other_index = other.fulltext_index.as_deref().map(normalized);
+                if let (Some(index), Some(other_index)) = (index, other_index)
+                    && (index.starts_with(&other_index) || other_index.starts_with(&index))
+                {
+                    problems.push(format!(
+                        "users {} and {} share fulltext_index {}",
+                        other.user_id,
+                        user.user_id,
+                        index.display()
+                    ));
+                }
+            }
+        }
+    }
+
+    /// The owner, then everyone else allowed to capture.
+    pub fn all_users(&self) -> impl Iterator<Item = &Self> {
+        std::iter::once(self).chain(&self.users)
+    }
+
+    /// The settings for the user with this Telegram ID, if they're allowed
+    /// to capture.
+    pub fn for_user(&self, user_id: u64) -> Option<&Self> {
+        self.all_users().find(|u| u.user_id == user_id)
+    }
+
+    fn check_dirs(&self, problems: &mut Vec<String>) {
+        check_dir("save_dir", &self.save_dir, true, problems);
+        if self.read_dir != self.save_dir {
+            check_dir("read_dir", &self.read_dir, false, problems);
+        }
+        if let Some(cache_dir) = self.index_cache.as_deref().and_then(Path::parent) {
+            let cache_dir = if cache_dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                cache_dir
+            };
+            check_dir("index_cache directory", cache_dir, true, problems);
+        }
+        let trash_dir = normalized(&self.trash_dir);
+        if trash_dir.starts_with(normalized(&self.save_dir))
+            || trash_dir.starts_with(normalized(&self.read_dir))
+        {
+            problems.push(format!(
+                "trash_dir {} is inside save_dir or read_dir, so deleted notes would load again",
+                self.trash_dir.display()
+            ));
+        }
+    }
+
+    /// The configuration in effect, in config file syntax, for
+    /// `capturebot config check`. The bot token is redacted.
+    pub fn resolved_toml(&self) -> String {
+        toml::to_string_pretty(&self.to_file()).expect("the config should serialize")
+    }
+
+    fn to_file(&self) -> ConfigFile {
+        let relative = |path: &Path| {
+            path.strip_prefix(&self.save_dir)
+                .unwrap_or(path)
+                .to_path_buf()
+        };
+        ConfigFile {
+            user_id: Some(self.user_id),
+            save_dir: Some(self.save_dir.clone()),
+            read_dir: Some(self.read_dir.clone()),
+            backup_json: self.backup_json.clone(),
+            tolerant_load: Some(self.tolerant_load),
+            report_load_errors: Some(self.report_load_errors),
+            index_cache: self.index_cache.clone(),
+            fulltext_index: self.fulltext_index.clone(),
+            index_articles: Some(self.index_articles),
+            path_template: Some(self.path_template.to_string()),
+            note_format: Some(self.note_format.to_string()),
+            slug_style: Some(self.slug_style.to_string()),
+            inbox: match &self.capture_target {
+                CaptureTarget::Inbox(path) => Some(relative(path)),
+                _ => None,
+            },
+            dailies: match &self.capture_target {
+                CaptureTarget::Dailies(path) => Some(relative(path)),
+                _ => None,
+            },
+            timezone: Some(self.timezone.to_string()),
+            bot_token: self.bot_token.as_ref().map(BotToken::to_string),
+            bot_token_file: None,
+            state_dir: Some(self.state_dir.clone()),
+            trash_dir: Some(self.trash_dir.clone()),
+            bot_username: self.bot_username.clone(),
+            users: self
+                .users
+                .iter()
+                .map(|user| ConfigFile {
+                    state_dir: None,
+                    bot_username: None,
+                    ..user.to_file()
+                })
+                .collect(),
+            chats: self
+                .chats
+                .iter()
+                .map(|chat| ChatFile {
+                    chat_id: chat.chat_id,
+                    user_id: Some(chat.user_id),
+                    save_dir: Some(chat.save_dir.clone()),
+                    topics: chat
+                        .topics
+                        .iter()
+                        .map(|topic| TopicFile {
+                            thread_id: topic.thread_id,
+                            name: topic.name.clone(),
+                            save_dir: topic.save_dir.clone(),
+                            tag: topic.tag.clone(),
+                        })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Where a new note with the given context should be saved.
+    pub fn note_path(&self, context: &PathContext) -> PathBuf {
+        match self.note_format {
+            NoteFormat::OrgRoam => self.save_dir.join(self.path_template.render(context)),
+            NoteFormat::Denote => self.save_dir.join(denote::file_name(
+                context.date,
+                context.slug,
+                context.tags,
+            )),
+        }
     }
 
     #[cfg(test)]
@@ -34,7 +788,24 @@ impl CapturebotConfig {
             user_id: 12345,
             save_dir: PathBuf::from(format!("/tmp/test_out/{}/", test_name)),
             backup_json: Some(PathBuf::from("./test_backup.json".to_string())),
-	    read_dir: PathBuf::from(format!("/tmp/test_out/read/{}/", test_name))
+	    read_dir: PathBuf::from(format!("/tmp/test_out/read/{}/", test_name)),
+            tolerant_load: false,
+            report_load_errors: false,
+            index_cache: None,
+            fulltext_index: None,
+            index_articles: false,
+            path_template: PathTemplate::default(),
+            note_format: NoteFormat::default(),
+            slug_style: SlugStyle::default(),
+            capture_target: CaptureTarget::default(),
+            timezone: Timezone::default(),
+            bot_token: None,
+            state_dir: PathBuf::from(format!("/tmp/test_out/state/{}/", test_name)),
+            trash_dir: PathBuf::from(format!("/tmp/test_out/state/{}/trash/", test_name)),
+            users: Vec::new(),
+            chats: Vec::new(),
+            bot_username: Some("capturebot".to_string()),
+            capture_tags: Vec::new(),
         }
     }
 }
@@ -0,0 +1,47 @@
+use std::path::Path;
+
+use chrono::{DateTime, FixedOffset, NaiveDateTime};
+use slugify::slugify;
+
+static IDENTIFIER_FORMAT: &str = "%Y%m%dT%H%M%S";
+
+/// Denote's identifier for a note made at `date`, e.g. `20240506T070809`.
+pub fn identifier(date: DateTime<FixedOffset>) -> String {
+    date.format(IDENTIFIER_FORMAT).to_string()
+}
+
+pub fn is_identifier(s: &str) -> bool {
+    s.len() == 15
+        && s.char_indices()
+            .all(|(i, c)| if i == 8 { c == 'T' } else { c.is_ascii_digit() })
+}
+
+/// When the note with Denote identifier `id` was made.
+pub fn date_from_identifier(id: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(id, IDENTIFIER_FORMAT).ok()
+}
+
+/// Denote's `IDENTIFIER--title-slug__tag1_tag2.org` file name.
+pub fn file_name(date: DateTime<FixedOffset>, slug: &str, tags: &[String]) -> String {
+    let keywords: Vec<String> = tags
+        .iter()
+        .map(|t| slugify!(t, separator = ""))
+        .filter(|t| !t.is_empty())
+        .collect();
+    let mut name = identifier(date);
+    if !slug.is_empty() {
+        name.push_str(&format!("--{slug}"));
+    }
+    if !keywords.is_empty() {
+        name.push_str(&format!("__{}", keywords.join("_")));
+    }
+    name.push_str(".org");
+    name
+}
+
+/// The identifier at the start of a Denote file name, for files whose front
+/// matter lacks an `#+identifier:` line.
+pub fn identifier_from_path(path: &Path) -> Option<String> {
+    let name = path.file_name()?.to_str()?;
+    name.get(..15).filter(|id| is_identifier(id)).map(str::to_string)
+}
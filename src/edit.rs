@@ -0,0 +1,249 @@
+//! Changes to the org source of notes that are already saved, like the ones
+//! made from the buttons under an acknowledgement. Sources are those of
+//! whole files for file-level notes and of subtrees for heading notes.
+
+use crate::inbox::own_section_end;
+use crate::split_tags;
+
+static FILETAGS_KEY: &str = "#+filetags:";
+static TITLE_KEY: &str = "#+title:";
+static TODO_KEYWORDS: [&str; 2] = ["TODO", "DONE"];
+static PLANNING_KEYWORDS: [&str; 3] = ["SCHEDULED:", "DEADLINE:", "CLOSED:"];
+// the front matter that comes from the message, and so follows its edits
+static MESSAGE_KEYS: [&str; 3] = ["#+title:", "#+roam_refs:", ":roam_refs:"];
+
+fn line_end(source: &str, start: usize) -> usize {
+    source[start..].find('\n').map_or(source.len(), |i| start + i)
+}
+
+pub(crate) fn merged_tags(existing: &[String], tags: &[String]) -> Vec<String> {
+    let mut merged = existing.to_vec();
+    for tag in tags {
+        if !merged.contains(tag) {
+            merged.push(tag.clone());
+        }
+    }
+    merged
+}
+
+fn is_heading_line(line: &str) -> bool {
+    line.starts_with('*') && line.trim_start_matches('*').starts_with(' ')
+}
+
+// a heading line split into what comes before its tags, and the tags
+fn split_heading_tags(line: &str) -> (&str, Vec<String>) {
+    match line.rsplit_once(' ') {
+        Some((title, last)) if last.len() > 2 && last.starts_with(':') && last.ends_with(':') => {
+            (title.trim_end(), split_tags(last))
+        }
+        _ => (line.trim_end(), Vec::new()),
+    }
+}
+
+/// `source` with `tags` added to the note's tags, on the heading line for
+/// heading notes and in the `#+filetags:` line for files, which is added
+/// under the title if there isn't one.
+pub fn add_tags(source: &str, heading: bool, tags: &[String]) -> String {
+    if heading {
+        let end = line_end(source, 0);
+        let (title, existing) = split_heading_tags(&source[..end]);
+        let tags = merged_tags(&existing, tags);
+        return format!("{title} :{}:{}", tags.join(":"), &source[end..]);
+    }
+    let mut offset = 0;
+    for line in source.split_inclusive('\n') {
+        let key = line.get(..FILETAGS_KEY.len());
+        if key.is_some_and(|k| k.eq_ignore_ascii_case(FILETAGS_KEY)) {
+            let value = &line[FILETAGS_KEY.len()..];
+            let padding = value.len() - value.trim_start().len();
+            let tags = merged_tags(&split_tags(value.trim()), tags);
+            let end = line_end(source, offset);
+            return format!(
+                "{}:{}:{}",
+                &source[..offset + FILETAGS_KEY.len() + padding],
+                tags.join(":"),
+                &source[end..]
+            );
+        }
+        offset += line.len();
+    }
+    // line the new keyword up with the title, the way Denote's front
+    // matter is
+    let mut offset = 0;
+    let mut insert_at = None;
+    let mut padding = 1;
+    for line in source.split_inclusive('\n') {
+        let key = line.get(..TITLE_KEY.len());
+        if key.is_some_and(|k| k.eq_ignore_ascii_case(TITLE_KEY)) {
+            let value = &line[TITLE_KEY.len()..];
+            let title_width = TITLE_KEY.len() + value.len() - value.trim_start().len();
+            padding = title_width.saturating_sub(FILETAGS_KEY.len()).max(1);
+            insert_at = Some(offset + line.len());
+            break;
+        }
+        offset += line.len();
+    }
+    let insert_at = insert_at.unwrap_or(0);
+    let separator = if source[..insert_at].ends_with('\n') || insert_at == 0 { "" } else { "\n" };
+    format!(
+        "{}{separator}{FILETAGS_KEY}{}:{}:\n{}",
+        &source[..insert_at],
+        " ".repeat(padding),
+        tags.join(":"),
+        &source[insert_at..]
+    )
+}
+
+/// `source` with the note marked TODO: heading notes get the keyword on
+/// their heading, and since org has no TODO state for files, file notes get
+/// a TODO heading of their own. Notes that already have one are left alone.
+pub fn make_todo(source: &str, heading: bool, title: &str) -> String {
+    if heading {
+        let stars = source.chars().take_while(|c| *c == '*').count();
+        let rest = source[stars..].trim_start_matches(' ');
+        let keyword = rest.split([' ', '\n']).next().unwrap_or_default();
+        if TODO_KEYWORDS.contains(&keyword) {
+            return source.to_string();
+        }
+        return format!("{} TODO {rest}", &source[..stars]);
+    }
+    if source.lines().any(|l| l.starts_with("* TODO ")) {
+        return source.to_string();
+    }
+    let separator = if source.is_empty() || source.ends_with('\n') { "" } else { "\n" };
+    format!("{source}{separator}* TODO {title}\n")
+}
+
+/// `source` with a "Related" link to another note, as a child of the
+/// heading for heading notes and as a top-level heading for files.
+pub fn add_link(source: &str, heading: bool, link: &str) -> String {
+    if heading {
+        let stars = source.chars().take_while(|c| *c == '*').count();
+        let end = own_section_end(source);
+        let separator = if source[..end].ends_with('\n') { "" } else { "\n" };
+        return format!(
+            "{}{separator}{}* Related: {link}\n{}",
+            &source[..end],
+            "*".repeat(stars),
+            &source[end..]
+        );
+    }
+    let separator = if source.is_empty() || source.ends_with('\n') { "" } else { "\n" };
+    format!("{source}{separator}* Related: {link}\n")
+}
+
+// where the front of a note ends: the heading line, planning and property
+// drawer of a heading, or the property drawer and keywords atop a file
+fn front_end(source: &str, heading: bool) -> usize {
+    let mut end = 0;
+    let mut in_drawer = false;
+    for line in source.split_inclusive('\n') {
+        let trimmed = line.trim();
+        let front = if in_drawer {
+            in_drawer = !trimmed.eq_ignore_ascii_case(":END:");
+            true
+        } else if trimmed.eq_ignore_ascii_case(":PROPERTIES:") {
+            in_drawer = true;
+            true
+        } else if heading {
+            end == 0 || PLANNING_KEYWORDS.iter().any(|k| trimmed.starts_with(k))
+        } else {
+            trimmed.starts_with("#+")
+        };
+        if !front {
+            break;
+        }
+        end += line.len();
+    }
+    end
+}
+
+// the note's own text: whatever comes between its front and the first
+// heading after it
+fn text_span(source: &str, heading: bool) -> std::ops::Range<usize> {
+    let start = front_end(source, heading);
+    let mut end = start;
+    for line in source[start..].split_inclusive('\n') {
+        if is_heading_line(line) {
+            break;
+        }
+        end += line.len();
+    }
+    start..end
+}
+
+fn message_key(line: &str) -> Option<&'static str> {
+    let trimmed = line.trim_start();
+    MESSAGE_KEYS
+        .into_iter()
+        .find(|k| trimmed.get(..k.len()).is_some_and(|t| t.eq_ignore_ascii_case(k)))
+}
+
+/// `source` brought up to date with `fresh`, the source the note's message
+/// makes now that it's been edited. The text, title and refs come from
+/// `fresh`, and the tags of both are kept; everything else that was added
+/// to the note since it was captured, like its TODO state, other properties
+/// and the headings after its text, stays as it is.
+pub fn replace_text(source: &str, heading: bool, fresh: &str) -> String {
+    let fresh_front = &fresh[..front_end(fresh, heading)];
+    let mut merged = String::new();
+    let mut front = &source[..front_end(source, heading)];
+    if heading {
+        let (line, rest) = front.split_at(line_end(front, 0));
+        let (headline, tags) = split_heading_tags(line);
+        let stars = headline.chars().take_while(|c| *c == '*').count();
+        let keyword = headline[stars..]
+            .split_whitespace()
+            .next()
+            .filter(|k| TODO_KEYWORDS.contains(k));
+        let fresh_line = &fresh_front[..line_end(fresh_front, 0)];
+        let fresh_title = split_heading_tags(fresh_line).0.trim_start_matches('*').trim_start();
+        merged.push_str(&headline[..stars]);
+        if let Some(keyword) = keyword {
+            merged.push_str(&format!(" {keyword}"));
+        }
+        merged.push_str(&format!(" {fresh_title}"));
+        if !tags.is_empty() {
+            merged.push_str(&format!(" :{}:", tags.join(":")));
+        }
+        front = rest;
+    }
+    let fresh_line = |key| fresh_front.split_inclusive('\n').find(|l| message_key(l) == Some(key));
+    let mut missing: Vec<&str> = MESSAGE_KEYS
+        .into_iter()
+        .filter(|k| !front.split_inclusive('\n').any(|l| message_key(l) == Some(k)))
+        .filter_map(fresh_line)
+        .collect();
+    for line in front.split_inclusive('\n') {
+        if line.trim().eq_ignore_ascii_case(":END:")
+            && let Some(at) = missing.iter().position(|l| l.starts_with(':'))
+        {
+            merged.push_str(missing.remove(at));
+        }
+        match message_key(line) {
+            Some(key) => merged.push_str(fresh_line(key).unwrap_or_default()),
+            None => merged.push_str(line),
+        }
+    }
+    missing.retain(|l| l.starts_with("#+"));
+    merged.extend(missing);
+    merged.push_str(&fresh[text_span(fresh, heading)]);
+    merged.push_str(&source[text_span(source, heading).end..]);
+    let fresh_tags = if heading {
+        split_heading_tags(&fresh[..line_end(fresh, 0)]).1
+    } else {
+        fresh_front
+            .lines()
+            .find_map(|l| {
+                let key = l.get(..FILETAGS_KEY.len())?;
+                key.eq_ignore_ascii_case(FILETAGS_KEY)
+                    .then(|| split_tags(&l[FILETAGS_KEY.len()..]))
+            })
+            .unwrap_or_default()
+    };
+    if fresh_tags.is_empty() {
+        merged
+    } else {
+        add_tags(&merged, heading, &fresh_tags)
+    }
+}
@@ -0,0 +1,563 @@
+//! The full-text search index, an on-disk tantivy index of every note's
+//! title and text, and optionally of the pages linked from captures. It's
+//! brought up to date whenever notes are loaded, captured, reloaded or
+//! deleted, so searching never has to read the notes themselves.
+//!
+//! Tantivy does blocking I/O, so everything that touches the index runs on
+//! tokio's blocking threads.
+
+use std::collections::HashMap;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use chrono::{NaiveDate, Utc};
+use tantivy::collector::TopDocs;
+use tantivy::query::{
+    AllQuery, BooleanQuery, BoostQuery, ConstScoreQuery, Occur, PhraseQuery, Query as TantivyQuery,
+    RangeQuery, RegexQuery, TermQuery,
+};
+use tantivy::schema::{
+    Field, IndexRecordOption, Schema, TextFieldIndexing, TextOptions, Value, FAST, INDEXED, STORED,
+    STRING,
+};
+use tantivy::{DocId, Index, Score, Searcher, SegmentReader, TantivyDocument, Term};
+use tokio::fs;
+use tokio::sync::Mutex;
+
+use crate::search::{note_text, snippet, Query, SearchHit};
+use crate::{read_note_source, CapturebotConfig, CapturebotNote};
+
+// a match in the title counts for this many in the text, and one in a
+// linked page for this many
+const TITLE_BOOST: f32 = 3.0;
+const ARTICLE_BOOST: f32 = 0.5;
+// a note this many days old gets half the boost of one captured today
+const RECENCY_HALF_LIFE_DAYS: f32 = 180.0;
+// tantivy won't take less than 15MB
+const WRITER_MEMORY: usize = 20_000_000;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+// in characters, so a huge page doesn't bloat the index
+const MAX_ARTICLE_LENGTH: usize = 100_000;
+
+// tantivy lets one writer at a time have an index, so the bot's own writes
+// wait for each other instead of failing
+static WRITING: Mutex<()> = Mutex::const_new(());
+
+struct Fields {
+    capturebot_id: Field,
+    title: Field,
+    text: Field,
+    article: Field,
+    // lowercased, for the filters
+    tags: Field,
+    refs: Field,
+    // seconds since the epoch
+    created: Field,
+    // the mtime of the note's file when it was indexed, in nanoseconds
+    modified: Field,
+}
+
+fn schema() -> (Schema, Fields) {
+    let stemmed = TextOptions::default()
+        .set_indexing_options(
+            TextFieldIndexing::default()
+                .set_tokenizer("en_stem")
+                .set_index_option(IndexRecordOption::WithFreqsAndPositions),
+        )
+        .set_stored();
+    let mut builder = Schema::builder();
+    let fields = Fields {
+        capturebot_id: builder.add_text_field("capturebot_id", STRING | STORED | FAST),
+        title: builder.add_text_field("title", stemmed.clone()),
+        text: builder.add_text_field("text", stemmed.clone()),
+        article: builder.add_text_field("article", stemmed),
+        tags: builder.add_text_field("tags", STRING | STORED),
+        refs: builder.add_text_field("refs", STRING | STORED),
+        created: builder.add_i64_field("created", INDEXED | FAST | STORED),
+        modified: builder.add_u64_field("modified", FAST | STORED),
+    };
+    (builder.build(), fields)
+}
+
+/// Opens the index in `dir`, creating it if there isn't one yet and
+/// starting over if it was made with another schema.
+fn open(dir: &Path) -> tantivy::Result<(Index, Fields)> {
+    let (schema, fields) = schema();
+    if dir.join("meta.json").exists() {
+        let index = Index::open_in_dir(dir)?;
+        if index.schema() == schema {
+            return Ok((index, fields));
+        }
+        log::info!("full-text index {} is from an older version, rebuilding", dir.display());
+        std::fs::remove_dir_all(dir)?;
+    }
+    std::fs::create_dir_all(dir)?;
+    Ok((Index::create_in_dir(dir, schema)?, fields))
+}
+
+// runs `task` on a blocking thread
+async fn blocking<T: Send + 'static>(
+    task: impl FnOnce() -> tantivy::Result<T> + Send + 'static,
+) -> tantivy::Result<T> {
+    tokio::task::spawn_blocking(task)
+        .await
+        .map_err(|e| tantivy::TantivyError::InternalError(e.to_string()))?
+}
+
+fn id_term(fields: &Fields, capturebot_id: &str) -> Term {
+    Term::from_field_text(fields.capturebot_id, capturebot_id)
+}
+
+fn stored_str(doc: &TantivyDocument, field: Field) -> Option<&str> {
+    doc.get_first(field).and_then(|v| v.as_str())
+}
+
+// the indexed note with this capturebot ID
+fn stored_doc(
+    searcher: &Searcher,
+    fields: &Fields,
+    capturebot_id: &str,
+) -> tantivy::Result<Option<TantivyDocument>> {
+    let query = TermQuery::new(id_term(fields, capturebot_id), IndexRecordOption::Basic);
+    match searcher.search(&query, &TopDocs::with_limit(1))?.first() {
+        Some((_, address)) => Ok(Some(searcher.doc(*address)?)),
+        None => Ok(None),
+    }
+}
+
+async fn modified(path: &Path) -> u64 {
+    fs::metadata(path)
+        .await
+        .ok()
+        .and_then(|m| m.modified().ok())
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_nanos() as u64)
+}
+
+/// What gets indexed of a note.
+struct Entry {
+    capturebot_id: String,
+    title: String,
+    text: String,
+    tags: Vec<String>,
+    refs: Vec<String>,
+    created: Option<i64>,
+    modified: u64,
+}
+
+impl Entry {
+    async fn of(note: &CapturebotNote) -> Option<Self> {
+        let source = if note.body.is_empty() {
+            read_note_source(note)
+                .await
+                .inspect_err(|e| log::warn!("couldn't index {}: {e}", note.path.display()))
+                .ok()?
+        } else {
+            note.body.clone()
+        };
+        Some(Self {
+            capturebot_id: note.capturebot_id.clone(),
+            title: note.title.clone(),
+            text: note_text(&source),
+            tags: note.all_tags().map(|t| t.to_lowercase()).collect(),
+            refs: note.refs.iter().map(|r| r.to_lowercase()).collect(),
+            created: note.created.map(|c| c.and_utc().timestamp()),
+            modified: modified(&note.path).await,
+        })
+    }
+}
+
+/// Indexes `entries` afresh and drops the notes with the capturebot IDs in
+/// `removed`. Notes keep the page text they were indexed with.
+fn write_entries(dir: &Path, entries: Vec<Entry>, removed: &[String]) -> tantivy::Result<()> {
+    let (index, fields) = open(dir)?;
+    let searcher = index.reader()?.searcher();
+    let mut writer = index.writer_with_num_threads::<TantivyDocument>(1, WRITER_MEMORY)?;
+    for capturebot_id in removed {
+        writer.delete_term(id_term(&fields, capturebot_id));
+    }
+    for entry in entries {
+        let article = stored_doc(&searcher, &fields, &entry.capturebot_id)?
+            .and_then(|doc| stored_str(&doc, fields.article).map(str::to_string));
+        let mut doc = TantivyDocument::default();
+        doc.add_text(fields.capturebot_id, &entry.capturebot_id);
+        doc.add_text(fields.title, &entry.title);
+        doc.add_text(fields.text, &entry.text);
+        if let Some(article) = article {
+            doc.add_text(fields.article, article);
+        }
+        for tag in &entry.tags {
+            doc.add_text(fields.tags, tag);
+        }
+        for link in &entry.refs {
+            doc.add_text(fields.refs, link);
+        }
+        if let Some(created) = entry.created {
+            doc.add_i64(fields.created, created);
+        }
+        doc.add_u64(fields.modified, entry.modified);
+        writer.delete_term(id_term(&fields, &entry.capturebot_id));
+        writer.add_document(doc)?;
+    }
+    writer.commit()?;
+    Ok(())
+}
+
+async fn write(dir: &Path, notes: &[&CapturebotNote], removed: &[String]) -> tantivy::Result<()> {
+    let mut entries = Vec::new();
+    for note in notes {
+        entries.extend(Entry::of(note).await);
+    }
+    let _writing = WRITING.lock().await;
+    let dir = dir.to_path_buf();
+    let removed = removed.to_vec();
+    blocking(move || write_entries(&dir, entries, &removed)).await
+}
+
+// gives the indexed note with this capturebot ID `article` as its page
+// text, leaving the rest of it as it was indexed last
+fn write_article(dir: &Path, capturebot_id: &str, article: &str) -> tantivy::Result<()> {
+    let (index, fields) = open(dir)?;
+    let searcher = index.reader()?.searcher();
+    let Some(stored) = stored_doc(&searcher, &fields, capturebot_id)? else {
+        return Ok(());
+    };
+    let mut doc = TantivyDocument::default();
+    for (field, value) in stored.field_values() {
+        if field != fields.article {
+            doc.add_field_value(field, value);
+        }
+    }
+    doc.add_text(fields.article, article);
+    let mut writer = index.writer_with_num_threads::<TantivyDocument>(1, WRITER_MEMORY)?;
+    writer.delete_term(id_term(&fields, capturebot_id));
+    writer.add_document(doc)?;
+    writer.commit()?;
+    Ok(())
+}
+
+/// Indexes `article` as the text of the page linked from the note with this
+/// capturebot ID, as long as the note is still indexed. The rest of the
+/// note's entry stays as it is, so that a fetch that finishes after the note
+/// was edited or deleted doesn't bring back what it was.
+pub(crate) async fn index_article(
+    dir: &Path,
+    capturebot_id: &str,
+    article: String,
+) -> tantivy::Result<()> {
+    let _writing = WRITING.lock().await;
+    let dir = dir.to_path_buf();
+    let capturebot_id = capturebot_id.to_string();
+    blocking(move || write_article(&dir, &capturebot_id, &article)).await
+}
+
+// the mtime each indexed note's file had when it was indexed, read from the
+// fast fields rather than the stored documents
+fn indexed_times(dir: &Path) -> tantivy::Result<HashMap<String, u64>> {
+    let (index, _) = open(dir)?;
+    let searcher = index.reader()?.searcher();
+    let mut indexed = HashMap::new();
+    for segment in searcher.segment_readers() {
+        let Some(ids) = segment.fast_fields().str("capturebot_id")? else {
+            continue;
+        };
+        let modified = segment.fast_fields().u64("modified")?;
+        let mut capturebot_id = String::new();
+        for doc in segment.doc_ids_alive() {
+            let Some(ord) = ids.term_ords(doc).next() else {
+                continue;
+            };
+            capturebot_id.clear();
+            ids.ord_to_str(ord, &mut capturebot_id)?;
+            indexed.insert(capturebot_id.clone(), modified.first(doc).unwrap_or_default());
+        }
+    }
+    Ok(indexed)
+}
+
+async fn sync_index(dir: &Path, notes: &HashMap<String, CapturebotNote>) -> tantivy::Result<usize> {
+    let indexed = {
+        let dir = dir.to_path_buf();
+        blocking(move || indexed_times(&dir)).await?
+    };
+    let mut file_times: HashMap<&PathBuf, u64> = HashMap::new();
+    let mut changed = Vec::new();
+    for note in notes.values() {
+        let modified = match file_times.get(&note.path) {
+            Some(modified) => *modified,
+            None => {
+                let modified = modified(&note.path).await;
+                file_times.insert(&note.path, modified);
+                modified
+            }
+        };
+        if indexed.get(&note.capturebot_id) != Some(&modified) {
+            changed.push(note);
+        }
+    }
+    let removed: Vec<String> = indexed.into_keys().filter(|id| !notes.contains_key(id)).collect();
+    if changed.is_empty() && removed.is_empty() {
+        return Ok(0);
+    }
+    write(dir, &changed, &removed).await?;
+    Ok(changed.len() + removed.len())
+}
+
+/// Brings the full-text index of `config`, if it has one, in line with
+/// `notes`: notes whose file changed since they were indexed are indexed
+/// again, and notes that are gone are dropped. A failure only costs search
+/// results, so it's logged rather than returned.
+pub async fn sync(notes: &HashMap<String, CapturebotNote>, config: &CapturebotConfig) {
+    let Some(dir) = &config.fulltext_index else {
+        return;
+    };
+    match sync_index(dir, notes).await {
+        Ok(0) => {}
+        Ok(count) => log::info!("updated {count} notes in the full-text index"),
+        Err(e) => log::warn!("couldn't update the full-text index {}: {e}", dir.display()),
+    }
+}
+
+/// Indexes `notes` again and drops the notes with the capturebot IDs in
+/// `removed` from the full-text index of `config`, if it has one.
+pub async fn update(notes: &[&CapturebotNote], removed: &[String], config: &CapturebotConfig) {
+    let Some(dir) = &config.fulltext_index else {
+        return;
+    };
+    if let Err(e) = write(dir, notes, removed).await {
+        log::warn!("couldn't update the full-text index {}: {e}", dir.display());
+    }
+}
+
+/// Indexes a new capture. With `index_articles` set, the page its first
+/// link points to is fetched in the background and indexed along with it.
+pub async fn index_capture(note: &CapturebotNote, config: &CapturebotConfig) {
+    update(&[note], &[], config).await;
+    let (Some(dir), true) = (&config.fulltext_index, config.index_articles) else {
+        return;
+    };
+    let Some(url) = note.refs.iter().find(|r| r.starts_with("http")).cloned() else {
+        return;
+    };
+    let dir = dir.clone();
+    let capturebot_id = note.capturebot_id.clone();
+    tokio::spawn(async move {
+        let Some(article) = fetch_article(&url).await else {
+            return;
+        };
+        if let Err(e) = index_article(&dir, &capturebot_id, article).await {
+            log::warn!("couldn't index the page at {url}: {e}");
+        }
+    });
+}
+
+async fn fetch_article(url: &str) -> Option<String> {
+    let response = reqwest::Client::builder()
+        .timeout(FETCH_TIMEOUT)
+        .build()
+        .ok()?
+        .get(url)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .inspect_err(|e| log::warn!("couldn't fetch {url}: {e}"))
+        .ok()?;
+    let is_html = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|t| t.contains("html"));
+    if !is_html {
+        return None;
+    }
+    let html = response.text().await.ok()?;
+    Some(html_text(&html).chars().take(MAX_ARTICLE_LENGTH).collect())
+}
+
+/// The readable text of an HTML page: everything outside its tags, without
+/// scripts and styles, on one line.
+pub(crate) fn html_text(html: &str) -> String {
+    let mut text = String::new();
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        text.push(' ');
+        let tag = &rest[start + 1..];
+        let name = tag
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let end = if ["script", "style", "noscript"].contains(&name.as_str()) {
+            let closing = format!("</{name}");
+            tag.to_ascii_lowercase()
+                .find(&closing)
+                .and_then(|i| tag[i..].find('>').map(|j| i + j))
+        } else {
+            tag.find('>')
+        };
+        rest = end.map_or("", |end| &tag[end + 1..]);
+    }
+    text.push_str(rest);
+    let text = text
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&");
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// matches `term` in the title, text or linked page, as a phrase if it's
+// more than one word once tokenized
+fn term_query(
+    index: &Index,
+    fields: &Fields,
+    term: &str,
+) -> tantivy::Result<Option<Box<dyn TantivyQuery>>> {
+    let mut clauses: Vec<(Occur, Box<dyn TantivyQuery>)> = Vec::new();
+    for (field, boost) in [
+        (fields.title, TITLE_BOOST),
+        (fields.text, 1.0),
+        (fields.article, ARTICLE_BOOST),
+    ] {
+        let mut analyzer = index.tokenizer_for_field(field)?;
+        let mut tokens = Vec::new();
+        analyzer
+            .token_stream(term)
+            .process(&mut |token| tokens.push(Term::from_field_text(field, &token.text)));
+        let query: Box<dyn TantivyQuery> = match tokens.len() {
+            0 => continue,
+            1 => Box::new(TermQuery::new(tokens.remove(0), IndexRecordOption::WithFreqs)),
+            _ => Box::new(PhraseQuery::new(tokens)),
+        };
+        clauses.push((Occur::Should, Box::new(BoostQuery::new(query, boost))));
+    }
+    Ok((!clauses.is_empty()).then(|| Box::new(BooleanQuery::new(clauses)) as Box<dyn TantivyQuery>))
+}
+
+// a filter, which narrows the results down without changing their order
+fn filter(query: impl TantivyQuery) -> Box<dyn TantivyQuery> {
+    Box::new(ConstScoreQuery::new(Box::new(query), 0.0))
+}
+
+fn regex_escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        if "\\.+*?()|[]{}^$#&-~\"<>@".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn midnight(date: NaiveDate) -> i64 {
+    date.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc().timestamp()
+}
+
+/// `query` as a tantivy query, with its filters as part of it, or nothing if
+/// one of its terms can't match anything.
+fn index_query(
+    index: &Index,
+    fields: &Fields,
+    query: &Query,
+) -> tantivy::Result<Option<BooleanQuery>> {
+    let mut clauses = Vec::new();
+    for term in &query.terms {
+        // a term of nothing but punctuation can't match anything
+        let Some(term_query) = term_query(index, fields, term)? else {
+            return Ok(None);
+        };
+        clauses.push((Occur::Must, term_query));
+    }
+    if clauses.is_empty() {
+        clauses.push((Occur::Must, Box::new(AllQuery)));
+    }
+    let tag_query = |tag: &str| {
+        filter(TermQuery::new(Term::from_field_text(fields.tags, tag), IndexRecordOption::Basic))
+    };
+    for tag in &query.tags {
+        clauses.push((Occur::Must, tag_query(tag)));
+    }
+    for tag in &query.without_tags {
+        clauses.push((Occur::MustNot, tag_query(tag)));
+    }
+    for url in &query.urls {
+        let pattern = format!(".*{}.*", regex_escape(url));
+        clauses.push((Occur::Must, filter(RegexQuery::from_pattern(&pattern, fields.refs)?)));
+    }
+    if query.before.is_some() || query.after.is_some() {
+        let bound = |date: Option<NaiveDate>, inclusive: bool| match date {
+            Some(date) if inclusive => Bound::Included(Term::from_field_i64(fields.created, midnight(date))),
+            Some(date) => Bound::Excluded(Term::from_field_i64(fields.created, midnight(date))),
+            None => Bound::Unbounded,
+        };
+        let range = RangeQuery::new(bound(query.after, true), bound(query.before, false));
+        clauses.push((Occur::Must, filter(range)));
+    }
+    Ok(Some(BooleanQuery::new(clauses)))
+}
+
+// how much a note created at `created` seconds counts for, by its age
+fn recency(created: Option<i64>, now: i64) -> f32 {
+    created.map_or(1.0, |created| {
+        let age = (now - created).max(0) as f32 / 86_400.0;
+        1.0 + 0.5f32.powf(age / RECENCY_HALF_LIFE_DAYS)
+    })
+}
+
+// a note found in the index: its capturebot ID, text and score
+type IndexHit = (String, String, f32);
+
+fn search_index(dir: &Path, query: &Query, limit: usize) -> tantivy::Result<Vec<IndexHit>> {
+    let (index, fields) = open(dir)?;
+    let Some(index_query) = index_query(&index, &fields, query)? else {
+        return Ok(Vec::new());
+    };
+    let searcher = index.reader()?.searcher();
+    let now = Utc::now().naive_utc().and_utc().timestamp();
+    let newest_first = TopDocs::with_limit(limit.max(1)).tweak_score(move |segment: &SegmentReader| {
+        let created = segment.fast_fields().i64("created").ok();
+        move |doc: DocId, score: Score| {
+            score * recency(created.as_ref().and_then(|c| c.first(doc)), now)
+        }
+    });
+    let mut hits = Vec::new();
+    for (score, address) in searcher.search(&index_query, &newest_first)? {
+        let doc: TantivyDocument = searcher.doc(address)?;
+        if let Some(capturebot_id) = stored_str(&doc, fields.capturebot_id) {
+            let text = stored_str(&doc, fields.text).unwrap_or_default();
+            hits.push((capturebot_id.to_string(), text.to_string(), score));
+        }
+    }
+    Ok(hits)
+}
+
+/// The best `limit` matches for `query` among `notes` in the full-text index
+/// in `dir`. Words are matched by their stems, quoted phrases as phrases,
+/// and a match's relevance counts for more the newer its note is.
+pub async fn search<'a>(
+    dir: &Path,
+    query: &Query,
+    notes: &'a HashMap<String, CapturebotNote>,
+    limit: usize,
+) -> tantivy::Result<Vec<SearchHit<'a>>> {
+    let found = {
+        let (dir, query) = (dir.to_path_buf(), query.clone());
+        blocking(move || search_index(&dir, &query, limit)).await?
+    };
+    Ok(found
+        .into_iter()
+        .filter_map(|(capturebot_id, text, score)| {
+            Some(SearchHit {
+                note: notes.get(&capturebot_id)?,
+                snippet: snippet(&text, &query.terms),
+                score,
+            })
+        })
+        .collect())
+}
@@ -0,0 +1,172 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use tokio::task::spawn_blocking;
+
+use crate::heading_span;
+use crate::write::replace_file;
+
+// how long to wait for someone else's lock on the inbox before giving up
+const LOCK_ATTEMPTS: usize = 20;
+const LOCK_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+static LOCK_OWNER_PREFIX: &str = "capturebot@";
+
+/// The `.#name` lock file Emacs creates next to a file it has unsaved
+/// changes to.
+fn emacs_lock_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".#{name}"))
+}
+
+fn lock_owner() -> String {
+    let host = std::env::var("HOSTNAME").unwrap_or("localhost".to_string());
+    format!("{LOCK_OWNER_PREFIX}{host}.{}", std::process::id())
+}
+
+/// A lock left behind by a capturebot process that no longer exists.
+fn is_stale(owner: &Path) -> bool {
+    owner
+        .to_str()
+        .and_then(|o| o.strip_prefix(LOCK_OWNER_PREFIX))
+        .and_then(|o| o.rsplit_once('.'))
+        .is_some_and(|(_, pid)| !Path::new("/proc").join(pid).exists())
+}
+
+/// Holds an Emacs-style lock on a file for as long as it lives, so that Emacs
+/// warns before editing it and we don't write under Emacs's unsaved changes.
+struct EmacsLock(PathBuf);
+
+impl EmacsLock {
+    async fn acquire(path: &Path) -> Result<Self, std::io::Error> {
+        let lock_path = emacs_lock_path(path);
+        for _ in 0..LOCK_ATTEMPTS {
+            match std::os::unix::fs::symlink(lock_owner(), &lock_path) {
+                Ok(()) => return Ok(Self(lock_path)),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    if let Ok(owner) = std::fs::read_link(&lock_path)
+                        && is_stale(&owner)
+                    {
+                        log::warn!("removing stale lock {}", lock_path.display());
+                        std::fs::remove_file(&lock_path).ok();
+                        continue;
+                    }
+                    tokio::time::sleep(LOCK_RETRY_DELAY).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        let owner = std::fs::read_link(&lock_path).unwrap_or_default();
+        Err(std::io::Error::new(
+            std::io::ErrorKind::WouldBlock,
+            format!("{} is locked by {}", path.display(), owner.display()),
+        ))
+    }
+}
+
+impl Drop for EmacsLock {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.0).ok();
+    }
+}
+
+/// Appends `heading` to the org file at `path`. If the file doesn't exist
+/// yet, it's created starting with `header`.
+///
+/// The append happens under both Emacs's lock file convention and an OS
+/// file lock, so it neither races other capturebot processes nor lands
+/// under a buffer someone is editing in Emacs.
+pub async fn append_heading(path: &Path, heading: &str, header: &str) -> Result<(), std::io::Error> {
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let _lock = EmacsLock::acquire(path).await?;
+    let path = path.to_path_buf();
+    let heading = heading.to_string();
+    let header = header.to_string();
+    spawn_blocking(move || {
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        file.lock()?;
+        // make sure the heading starts on a line of its own
+        let mut separator = "";
+        if file.metadata()?.len() > 0 {
+            let mut last = [0u8];
+            file.seek(SeekFrom::End(-1))?;
+            file.read_exact(&mut last)?;
+            if last[0] != b'\n' {
+                separator = "\n";
+            }
+        } else {
+            separator = &header;
+        }
+        file.write_all(format!("{separator}{heading}").as_bytes())?;
+        file.sync_all()?;
+        file.unlock()
+    })
+    .await?
+}
+
+/// Inserts `heading` at the end of the subtree of the heading with ID
+/// `parent_id` in the org file at `path`, under the same Emacs lock as
+/// `append_heading`.
+pub async fn insert_heading(path: &Path, heading: &str, parent_id: &str) -> Result<(), std::io::Error> {
+    let _lock = EmacsLock::acquire(path).await?;
+    let mut source = tokio::fs::read_to_string(path).await?;
+    let span = heading_span(&source, parent_id).ok_or(std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("couldn't find heading {parent_id} in {}", path.display()),
+    ))?;
+    let mut insertion = heading.to_string();
+    if !source[..span.end].ends_with('\n') {
+        insertion.insert(0, '\n');
+    }
+    source.insert_str(span.end, &insertion);
+    replace_file(path, &source).await
+}
+
+/// The end of the part of `subtree` that a capture renders itself: the
+/// heading, its text and its "Related" link, but not the captures nested
+/// under it.
+pub(crate) fn own_section_end(subtree: &str) -> usize {
+    let stars = subtree.chars().take_while(|c| *c == '*').count();
+    let related = format!("{}* Related: ", "*".repeat(stars));
+    let mut end = subtree.find('\n').map_or(subtree.len(), |i| i + 1);
+    let mut seen_child = false;
+    while end < subtree.len() {
+        let line_end = subtree[end..].find('\n').map_or(subtree.len(), |i| end + i + 1);
+        let line = &subtree[end..line_end];
+        if line.starts_with('*') {
+            if seen_child || !line.starts_with(&related) {
+                break;
+            }
+            seen_child = true;
+        }
+        end = line_end;
+    }
+    end
+}
+
+/// Replaces the subtree of the heading with ID `org_id` in the org file at
+/// `path` with what `edit` makes of it, under the same Emacs lock as
+/// `append_heading`. Returns the new subtree.
+pub async fn edit_heading(
+    path: &Path,
+    org_id: &str,
+    edit: impl FnOnce(&str) -> String,
+) -> Result<String, std::io::Error> {
+    let _lock = EmacsLock::acquire(path).await?;
+    let mut source = tokio::fs::read_to_string(path).await?;
+    let span = heading_span(&source, org_id).ok_or(std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("couldn't find heading {org_id} in {}", path.display()),
+    ))?;
+    let subtree = edit(&source[span.clone()]);
+    source.replace_range(span, &subtree);
+    replace_file(path, &source).await?;
+    Ok(subtree)
+}
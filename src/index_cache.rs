@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::CapturebotNote;
+
+// bump this whenever the cached note format changes, so stale caches get
+// thrown away instead of misread
+const CACHE_VERSION: u32 = 5;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedFile {
+    mtime: SystemTime,
+    size: u64,
+    notes: Vec<CapturebotNote>,
+}
+
+/// On-disk index of the notes found in each org file, keyed by path and
+/// invalidated by mtime and size.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NoteIndexCache {
+    version: u32,
+    files: HashMap<PathBuf, CachedFile>,
+}
+
+impl Default for NoteIndexCache {
+    fn default() -> Self {
+        Self {
+            version: CACHE_VERSION,
+            files: HashMap::new(),
+        }
+    }
+}
+
+impl NoteIndexCache {
+    /// Reads the cache at `path`. A missing, unreadable or outdated cache
+    /// just means every file gets parsed again, so this never fails.
+    pub async fn load(path: &Path) -> Self {
+        let cache = match fs::read_to_string(path).await {
+            Ok(s) => serde_json::from_str::<Self>(&s)
+                .inspect_err(|e| log::warn!("discarding unreadable index cache: {e}"))
+                .unwrap_or_default(),
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    log::warn!("couldn't read index cache {}: {e}", path.display());
+                }
+                Self::default()
+            }
+        };
+        if cache.version == CACHE_VERSION {
+            cache
+        } else {
+            log::info!("index cache is from an older version, rebuilding");
+            Self::default()
+        }
+    }
+
+    pub async fn save(&self, path: &Path) -> Result<(), std::io::Error> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::write(path, serde_json::to_vec(self)?).await
+    }
+
+    /// The cached notes for `path`, if the file hasn't changed since they
+    /// were recorded.
+    pub fn lookup(&self, path: &Path, metadata: &Metadata) -> Option<&[CapturebotNote]> {
+        let cached = self.files.get(path)?;
+        (metadata.modified().ok()? == cached.mtime && metadata.len() == cached.size)
+            .then_some(cached.notes.as_slice())
+    }
+
+    pub fn record(&mut self, path: &Path, metadata: &Metadata, notes: Vec<CapturebotNote>) {
+        if let Ok(mtime) = metadata.modified() {
+            self.files.insert(
+                path.to_path_buf(),
+                CachedFile {
+                    mtime,
+                    size: metadata.len(),
+                    notes,
+                },
+            );
+        }
+    }
+}
@@ -1,33 +1,177 @@
 #![feature(iter_intersperse)]
+pub mod access;
+pub mod ack;
 mod config;
+pub mod denote;
+mod edit;
+mod fulltext;
+mod inbox;
+mod index_cache;
+pub mod logging;
+pub mod path_template;
+pub mod search;
+pub mod slug;
 mod tests;
+pub mod timezone;
+pub mod watch;
+mod write;
 
-pub use crate::config::CapturebotConfig;
-use chrono::Utc;
+pub use crate::config::{
+    take_config_arg, BotToken, CaptureTarget, CapturebotConfig, ChatConfig, ConfigError, NoteFormat,
+    TopicConfig,
+};
+use crate::index_cache::NoteIndexCache;
+use crate::path_template::PathContext;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
 use organic::parser::parse_file;
-use organic::types::{Document, Heading, StandardProperties};
-use slugify::slugify;
+use organic::types::{Document, DocumentElement, Heading, StandardProperties};
+use serde::{Deserialize, Serialize};
 use std::collections::{self, HashMap};
 use std::io::Error;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use teloxide::types::{Message, MessageEntityKind};
 use tokio::fs;
+use tokio::sync::{Mutex, RwLock};
 use uuidgen::gen_uuid;
 use walkdir::WalkDir;
 
 pub static CAPTUREBOT_ID_PROPERTY: &str = "CAPTUREBOT_MESSAGE_ID";
 pub static CAPTUREBOT_PARENT_ID_PROPERTY: &str = "CAPTUREBOT_PARENT_MESSAGE_ID";
+pub static CAPTUREBOT_GROUP_PROPERTY: &str = "CAPTUREBOT_GROUP";
+pub static CAPTUREBOT_AUTHOR_PROPERTY: &str = "CAPTUREBOT_AUTHOR";
+pub static CAPTUREBOT_CHANNEL_PROPERTY: &str = "CAPTUREBOT_CHANNEL";
+/// The forum topic a note was captured in, as `THREAD_ID NAME`.
+pub static CAPTUREBOT_TOPIC_PROPERTY: &str = "CAPTUREBOT_TOPIC";
 
-#[derive(Debug)]
+static CAPTURE_COMMAND: &str = "/capture";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CapturebotNote {
     pub id: String,
     pub path: PathBuf,
     pub capturebot_id: String,
     pub _capturebot_parent: Option<String>,
     pub title: String,
+    pub refs: Vec<String>,
+    pub tags: Vec<String>,
+    /// Tags the note inherits from the `#+filetags:` of its file and, for
+    /// headings, from the headings it is nested under.
+    #[serde(default)]
+    pub inherited_tags: Vec<String>,
+    /// For notes that are headings rather than whole files, the titles of
+    /// the heading and its ancestors, outermost first. Empty for file-level
+    /// notes.
+    #[serde(default)]
+    pub outline_path: Vec<String>,
+    /// The forum topic the note was captured in, as recorded in its
+    /// `CAPTUREBOT_TOPIC` property.
+    #[serde(default)]
+    pub topic: Option<String>,
+    /// When the note was made, in the configured timezone, from its
+    /// `CREATED` property or Denote date.
+    #[serde(default)]
+    pub created: Option<NaiveDateTime>,
+    /// The full org source of the note. Left empty for notes loaded while
+    /// the index cache is enabled, so that it isn't held in memory.
+    #[serde(skip)]
     pub body: String,
 }
 
+impl CapturebotNote {
+    pub fn is_heading(&self) -> bool {
+        !self.outline_path.is_empty()
+    }
+
+    /// The note's own tags and the ones it inherits.
+    pub fn all_tags(&self) -> impl Iterator<Item = &String> {
+        self.inherited_tags.iter().chain(&self.tags)
+    }
+
+    /// An org link to this note, using Denote's link type for notes that
+    /// are identified by a Denote identifier.
+    pub fn org_link(&self) -> String {
+        format!("{}[{}]]", self.link_target(), self.title)
+    }
+
+    /// The start of every org link to this note, up to its description.
+    fn link_target(&self) -> String {
+        let link_type = if denote::is_identifier(&self.id) { "denote" } else { "id" };
+        format!("[[{link_type}:{}]", self.id)
+    }
+
+    /// The thread ID and name of the forum topic the note was captured in.
+    pub fn topic(&self) -> Option<(i32, &str)> {
+        let (thread_id, name) = self.topic.as_deref()?.split_once(' ')?;
+        Some((thread_id.parse().ok()?, name))
+    }
+}
+
+// the refs in a `ROAM_REFS` value, separated by commas or whitespace
+// except inside `[[...]]` links, whose descriptions can have spaces
+fn split_refs(refs: &str) -> Vec<String> {
+    let mut split = Vec::new();
+    let mut current = String::new();
+    let mut in_link = false;
+    let mut rest = refs;
+    while let Some(c) = rest.chars().next() {
+        if !in_link && rest.starts_with("[[") {
+            in_link = true;
+        } else if in_link && rest.starts_with("]]") {
+            in_link = false;
+            current.push_str("]]");
+            rest = &rest[2..];
+            continue;
+        } else if !in_link && (c == ',' || c.is_whitespace()) {
+            if !current.is_empty() {
+                split.push(std::mem::take(&mut current));
+            }
+            rest = &rest[c.len_utf8()..];
+            continue;
+        }
+        current.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    if !current.is_empty() {
+        split.push(current);
+    }
+    split
+}
+
+/// The date and time of an org timestamp like `[2024-05-06 Mon 07:08]`,
+/// at midnight if it has no time.
+fn parse_org_timestamp(timestamp: &str) -> Option<NaiveDateTime> {
+    let inner = timestamp.trim().trim_matches(['[', ']', '<', '>']);
+    let mut parts = inner.split_whitespace();
+    let date = NaiveDate::parse_from_str(parts.next()?, "%Y-%m-%d").ok()?;
+    let time = parts
+        .find_map(|p| NaiveTime::parse_from_str(p, "%H:%M").ok())
+        .unwrap_or_default();
+    Some(date.and_time(time))
+}
+
+/// The front matter keywords of an org file, which can stand in for file
+/// properties as `#+lowercase_property_name:`, the way Denote notes carry
+/// them.
+fn front_matter<'s>(doc: &Document<'s>) -> HashMap<String, &'s str> {
+    doc.zeroth_section
+        .iter()
+        .flat_map(|zeroth_section| zeroth_section.children.iter())
+        .filter_map(|e| match e {
+            organic::types::Element::Keyword(k) => Some((k.key.to_lowercase(), k.value)),
+            _ => None,
+        })
+        .collect()
+}
+
+fn split_tags(tags: &str) -> Vec<String> {
+    tags.split([':', ' '])
+        .filter(|t| !t.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
 pub trait ContextualFrom<S, X, C>: Sized {
     type Error;
     fn contextual_from(value: S, context: X, config: C) -> Result<Self, Self::Error>;
@@ -42,61 +186,76 @@ impl ContextualFrom<&Document<'_>, &HashMap<String, CapturebotNote>, &Capturebot
         _notes: &HashMap<String, CapturebotNote>,
         _config: &CapturebotConfig,
     ) -> Result<Self, Self::Error> {
-        let default_title = "untitled capturebot note".to_string();
-        let title = doc
-            .zeroth_section
-            .iter()
-            .flat_map(|zeroth_section| zeroth_section.children.iter())
-            .find_map(|e| match e {
-                organic::types::Element::Keyword(k) if k.key == "title" => Some(k.value),
-                _ => None,
-            })
-            .unwrap_or(&default_title)
-            .to_string();
+        let keywords = front_matter(doc);
+        let title = keywords
+            .get("title")
+            .map_or("untitled capturebot note".to_string(), |t| t.to_string());
+        let tags = keywords
+            .get("filetags")
+            .map_or(Vec::new(), |t| split_tags(t));
 
         let properties_iterator = doc
             .get_additional_properties()
             .filter_map(|p| p.value.map(|v| (p.property_name, v.to_string())));
         let properties_map: HashMap<&str, String> =
             collections::HashMap::from_iter(properties_iterator);
-        let cap_id = properties_map
-            .get(CAPTUREBOT_ID_PROPERTY)
+        let property = |name: &str| {
+            properties_map
+                .get(name)
+                .cloned()
+                .or(keywords.get(&name.to_lowercase()).map(|v| v.to_string()))
+        };
+        let cap_id = property(CAPTUREBOT_ID_PROPERTY).ok_or(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "this note has no capturebot id, dropping",
+        ))?;
+        let id = property("ID")
+            .or(keywords.get("identifier").map(|v| v.to_string()))
+            .or(doc.path.as_deref().and_then(denote::identifier_from_path))
             .ok_or(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
-                "this note has no capturebot id, dropping",
+                "this note has no id, dropping",
             ))?;
+        let created = property("CREATED")
+            .or(keywords.get("date").map(|d| d.to_string()))
+            .and_then(|t| parse_org_timestamp(&t))
+            .or_else(|| denote::date_from_identifier(&id));
         let note: CapturebotNote = CapturebotNote {
-            id: properties_map
-                .get("ID")
-                .ok_or(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "this note has no id, dropping",
-                ))?
-                .to_string(),
+            id,
             path: doc.path.clone().ok_or(Error::new(std::io::ErrorKind::InvalidData, "note should have a path"))?.to_path_buf(),
-            capturebot_id: cap_id.to_string(),
-            _capturebot_parent: properties_map.get(CAPTUREBOT_PARENT_ID_PROPERTY).cloned(),
+            capturebot_id: cap_id,
+            _capturebot_parent: property(CAPTUREBOT_PARENT_ID_PROPERTY),
             title,
+            refs: property("ROAM_REFS").map_or(Vec::new(), |r| split_refs(&r)),
+            tags,
+            inherited_tags: Vec::new(),
+            outline_path: Vec::new(),
+            topic: property(CAPTUREBOT_TOPIC_PROPERTY),
+            created,
             body: doc.source.to_string(),
         };
         Ok(note)
     }
 }
 
+fn heading_title(heading: &Heading) -> String {
+    heading
+        .title
+        .iter()
+        .map(|o| o.get_source())
+        .collect::<String>()
+}
+
 impl ContextualFrom<&Heading<'_>, &HashMap<String, CapturebotNote>, &CapturebotConfig>
     for CapturebotNote
 {
     type Error = std::io::Error;
     fn contextual_from<'a>(
         heading: &Heading,
-        notes: &HashMap<String, CapturebotNote>,
-        config: &CapturebotConfig,
+        _notes: &HashMap<String, CapturebotNote>,
+        _config: &CapturebotConfig,
     ) -> Result<Self, Self::Error> {
-        let title = heading
-            .title
-            .iter()
-            .map(|o| o.get_source())
-            .collect::<String>();
+        let title = heading_title(heading);
         let properties_iterator = heading
             .get_additional_properties()
             .filter_map(|p| p.value.map(|v| (p.property_name, v.to_string())));
@@ -119,84 +278,560 @@ impl ContextualFrom<&Heading<'_>, &HashMap<String, CapturebotNote>, &CapturebotC
             path: PathBuf::new(),
             capturebot_id: cap_id.to_string(),
             _capturebot_parent: properties_map.get(CAPTUREBOT_PARENT_ID_PROPERTY).cloned(),
-            title,
+            title: title.clone(),
+            refs: properties_map.get("ROAM_REFS").map_or(Vec::new(), |r| split_refs(r)),
+            tags: heading.tags.iter().map(|t| t.to_string()).collect(),
+            inherited_tags: Vec::new(),
+            outline_path: vec![title],
+            topic: properties_map.get(CAPTUREBOT_TOPIC_PROPERTY).cloned(),
+            created: properties_map
+                .get("CREATED")
+                .and_then(|t| parse_org_timestamp(t)),
             body: heading.get_source().to_string(),
         };
         Ok(note)
     }
 }
 
-impl ContextualFrom<Message, &HashMap<String, CapturebotNote>, &CapturebotConfig>
+/// A message on its way to becoming a note, with everything the note is made
+/// from. Every note producer builds one of these and leaves naming and
+/// formatting the note to its `ContextualFrom` impl, so that live captures
+/// and imported backups come out the same.
+#[derive(Debug, Clone)]
+pub struct Capture {
+    pub date: DateTime<Utc>,
+    pub text: String,
+    pub refs: Vec<String>,
+    pub tags: Vec<String>,
+    pub capturebot_id: String,
+    pub capturebot_parent: Option<String>,
+    pub chat: String,
+    /// Extra properties to record on the note, by name.
+    pub properties: Vec<(String, String)>,
+    /// The org ID to give the note, when it replaces an earlier version of
+    /// itself; new notes get a fresh one.
+    pub org_id: Option<String>,
+}
+
+impl ContextualFrom<Capture, &HashMap<String, CapturebotNote>, &CapturebotConfig>
     for CapturebotNote
 {
     type Error = std::io::Error;
     fn contextual_from(
-        msg: Message,
+        capture: Capture,
         notes: &HashMap<String, CapturebotNote>,
         config: &CapturebotConfig,
     ) -> Result<CapturebotNote, Self::Error> {
-        let text = msg.text().unwrap().to_string();
+        let Capture {
+            date,
+            text,
+            refs,
+            tags,
+            capturebot_id: cap_id,
+            capturebot_parent,
+            chat,
+            properties: extra_properties,
+            org_id,
+        } = capture;
         let title = text.lines().next().map_or(
             format!("capturebot note made at {}", Utc::now()),
             str::to_string,
         );
-        let links: String = msg
-            .parse_entities()
-            .unwrap_or_default()
+        let links: String = refs.iter().map(String::as_str).intersperse(", ").collect();
+        let topic = extra_properties
             .iter()
-            .filter_map(|m| match m.kind() {
-                MessageEntityKind::TextLink { url } => Some(url.as_str()),
-                MessageEntityKind::Url => Some(m.text()),
-                _ => None,
-            })
-            .intersperse(", ")
-            .collect();
-        let timestamp = msg.date.format("[%Y-%m-%d %a %H:%M]");
-        let org_id = gen_uuid(true);
-        let cap_id = msg.id.to_string();
-        let reply = msg.reply_to_message();
-        let cap_parent_id_property_string = reply.map_or(String::new(), |rt| {
-            format!("\n:{CAPTUREBOT_PARENT_ID_PROPERTY}: {}", rt.id.to_string())
+            .find(|(name, _)| name == CAPTUREBOT_TOPIC_PROPERTY)
+            .map(|(_, value)| value.clone());
+        // everything the note shows of its date is in the configured timezone
+        let mut date = config.timezone.localize(date);
+        let timestamp = date.format("[%Y-%m-%d %a %H:%M]");
+        let org_parent_link_string = capturebot_parent.as_ref().map_or(String::new(), |rt| {
+            notes
+                .get(rt)
+                .map_or(String::new(), |pn| format!("* Related: {}\n", pn.org_link()))
         });
-        let org_parent_link_string = reply.map_or(String::new(), |rt| {
-            notes.get(&rt.id.to_string()).map_or(String::new(), |pn| {
-                format!("* Related: [[id:{}][{}]]\n", pn.id.to_string(), pn.title)
-            })
+        // denote identifiers are timestamps, so like denote itself, step past
+        // any that are already taken
+        if config.note_format == NoteFormat::Denote {
+            while notes.values().any(|n| n.id == denote::identifier(date)) {
+                date += chrono::Duration::seconds(1);
+            }
+        }
+        let target_path = config.note_path(&PathContext {
+            date,
+            slug: &slug::slug(&title, config.slug_style),
+            tags: &tags,
+            chat: &chat,
+            id: &cap_id,
         });
-        let target_path = format!(
-            "{s}/{d}-{t}.org",
-            s = config.save_dir.display(),
-            d = msg.date.format("%Y%m%d%H%M%S"),
-            t = slugify!(&title, max_length = 30)
-        );
-        let note_body = format!(
-            ":PROPERTIES:
-:ID: {org_id}
+        let org_id = org_id.unwrap_or_else(|| gen_uuid(true));
+        let cap_parent_id_property_string = capturebot_parent
+            .as_ref()
+            .map_or(String::new(), |rt| format!("\n:{CAPTUREBOT_PARENT_ID_PROPERTY}: {rt}"));
+        let extra_properties_string: String = extra_properties
+            .iter()
+            .map(|(name, value)| format!("\n:{name}: {value}"))
+            .collect();
+        let properties = format!(
+            ":ID: {org_id}
 :CREATED: {timestamp}
-:{CAPTUREBOT_ID_PROPERTY}: {cap_id}{cap_parent_id_property_string}
+:{CAPTUREBOT_ID_PROPERTY}: {cap_id}{cap_parent_id_property_string}{extra_properties_string}
 :ROAM_REFS: {links}
-:END:
+"
+        );
+        let parent = capturebot_parent.as_ref().and_then(|rt| notes.get(rt));
+        let heading_target = match &config.capture_target {
+            CaptureTarget::Files => None,
+            CaptureTarget::Inbox(inbox) => Some((inbox.clone(), None, text.clone())),
+            CaptureTarget::Dailies(dailies) => {
+                let day = date.format("%Y-%m-%d");
+                let daily = dailies.join(format!("{day}.org"));
+                // replies to an entry in the same daily go under that entry
+                let nest_under = parent.filter(|p| p.is_heading() && p.path == daily);
+                let (first_line, rest) = text.split_once('\n').unwrap_or((&text, ""));
+                let text = format!("{first_line}\n{timestamp}\n{rest}");
+                Some((daily, nest_under, text))
+            }
+        };
+        if let Some((path, nest_under, text)) = heading_target {
+            let mut outline_path = nest_under.map_or(Vec::new(), |p| p.outline_path.clone());
+            outline_path.push(title.clone());
+            let related = if nest_under.is_some() { None } else { parent };
+            return Ok(CapturebotNote {
+                id: org_id,
+                path,
+                capturebot_id: cap_id,
+                _capturebot_parent: capturebot_parent,
+                title: title.clone(),
+                refs,
+                body: render_heading(outline_path.len(), &title, &tags, &properties, &text, related),
+                tags,
+                inherited_tags: Vec::new(),
+                outline_path,
+                topic,
+                created: Some(date.naive_local()),
+            });
+        }
+        let (org_id, note_body) = match config.note_format {
+            NoteFormat::OrgRoam => {
+                let filetags_string = if tags.is_empty() {
+                    String::new()
+                } else {
+                    format!("#+filetags: :{}:\n", tags.join(":"))
+                };
+                let note_body = format!(
+                    ":PROPERTIES:
+{properties}:END:
 #+title: {title}
-{text}
+{filetags_string}{text}
 {org_parent_link_string}
 "
-        );
+                );
+                (org_id, note_body)
+            }
+            NoteFormat::Denote => {
+                let identifier = denote::identifier(date);
+                let mut front_matter = format!("#+title:      {title}\n#+date:       {timestamp}\n");
+                if !tags.is_empty() {
+                    front_matter.push_str(&format!("#+filetags:   :{}:\n", tags.join(":")));
+                }
+                front_matter.push_str(&format!("#+identifier: {identifier}\n"));
+                front_matter.push_str(&format!(
+                    "#+{}: {cap_id}\n",
+                    CAPTUREBOT_ID_PROPERTY.to_lowercase()
+                ));
+                if let Some(rt) = &capturebot_parent {
+                    front_matter.push_str(&format!(
+                        "#+{}: {rt}\n",
+                        CAPTUREBOT_PARENT_ID_PROPERTY.to_lowercase()
+                    ));
+                }
+                for (name, value) in &extra_properties {
+                    front_matter.push_str(&format!("#+{}: {value}\n", name.to_lowercase()));
+                }
+                if !links.is_empty() {
+                    front_matter.push_str(&format!("#+roam_refs: {links}\n"));
+                }
+                let note_body = format!("{front_matter}\n{text}\n{org_parent_link_string}\n");
+                (identifier, note_body)
+            }
+        };
         Ok(CapturebotNote {
             id: org_id,
-            path: PathBuf::from(target_path),
-            capturebot_id: msg.id.to_string(),
-            _capturebot_parent: msg.reply_to_message().map(|rt| rt.id.to_string()),
+            path: target_path,
+            capturebot_id: cap_id,
+            _capturebot_parent: capturebot_parent,
             title,
+            refs,
+            tags,
+            inherited_tags: Vec::new(),
+            outline_path: Vec::new(),
+            topic,
+            created: Some(date.naive_local()),
             body: note_body,
         })
     }
 }
 
+/// Renders a capture as an org heading at `level`, for captures that go into
+/// a shared file instead of one of their own. The first line of `text` is
+/// the heading title; lines of the rest that would start a heading of their
+/// own are indented so that they stay inside the capture.
+fn render_heading(
+    level: usize,
+    title: &str,
+    tags: &[String],
+    properties: &str,
+    text: &str,
+    related: Option<&CapturebotNote>,
+) -> String {
+    let stars = "*".repeat(level);
+    let tags_string = if tags.is_empty() {
+        String::new()
+    } else {
+        format!(" :{}:", tags.join(":"))
+    };
+    let mut heading = format!("{stars} {title}{tags_string}\n:PROPERTIES:\n{properties}:END:\n");
+    for line in text.lines().skip(1) {
+        if line.starts_with('*') {
+            heading.push(' ');
+        }
+        heading.push_str(line);
+        heading.push('\n');
+    }
+    if let Some(related) = related {
+        heading.push_str(&format!("{stars}* Related: {}\n", related.org_link()));
+    }
+    heading
+}
+
+/// The rest of `text` if it starts with `command`, either bare or addressed
+/// to this bot as in `/capture@bot`.
+pub fn strip_command<'a>(
+    text: &'a str,
+    command: &str,
+    bot_username: Option<&str>,
+) -> Option<&'a str> {
+    let mut rest = text.strip_prefix(command)?;
+    if let Some(addressed) = rest.strip_prefix('@') {
+        let end = addressed.find(char::is_whitespace).unwrap_or(addressed.len());
+        if !bot_username.is_some_and(|bot| addressed[..end].eq_ignore_ascii_case(bot)) {
+            return None;
+        }
+        rest = &addressed[end..];
+    }
+    // "/captured" isn't the command
+    (rest.is_empty() || rest.starts_with(char::is_whitespace)).then(|| rest.trim_start())
+}
+
+fn mentions_bot(msg: &Message, bot_username: Option<&str>) -> bool {
+    let Some(bot) = bot_username else {
+        return false;
+    };
+    msg.parse_entities().unwrap_or_default().iter().any(|e| {
+        matches!(e.kind(), MessageEntityKind::Mention)
+            && e.text().trim_start_matches('@').eq_ignore_ascii_case(bot)
+    })
+}
+
+/// Whether a group chat message is meant for the bot: it mentions the bot,
+/// uses `/capture`, or replies to a message that used `/capture`.
+fn is_group_capture(msg: &Message, bot_username: Option<&str>) -> bool {
+    let asks = |m: &Message| {
+        m.text()
+            .is_some_and(|t| strip_command(t, CAPTURE_COMMAND, bot_username).is_some())
+    };
+    asks(msg) || mentions_bot(msg, bot_username) || replied_to(msg).is_some_and(asks)
+}
+
+/// The message `msg` replies to. Messages in a forum topic count as replies
+/// to the message that created the topic, which isn't a reply as far as
+/// capturing goes.
+fn replied_to(msg: &Message) -> Option<&Message> {
+    msg.reply_to_message()
+        .filter(|replied| replied.forum_topic_created().is_none())
+}
+
+/// The forum topic `msg` was posted in, as its thread ID and, when it's
+/// known, its name. The name comes from the message that created the topic,
+/// or failing that, from notes captured in the same topic before.
+pub fn message_topic(
+    msg: &Message,
+    notes: &HashMap<String, CapturebotNote>,
+) -> Option<(i32, Option<String>)> {
+    if !msg.is_topic_message {
+        return None;
+    }
+    let thread_id = msg.thread_id?.0.0;
+    let chat_prefix = format!("{}:", msg.chat.id);
+    let name = msg
+        .reply_to_message()
+        .and_then(Message::forum_topic_created)
+        .map(|created| created.name.clone())
+        .or_else(|| {
+            notes
+                .values()
+                .filter(|n| n.capturebot_id.starts_with(&chat_prefix))
+                .find_map(|n| n.topic().filter(|(id, _)| *id == thread_id))
+                .map(|(_, name)| name.to_string())
+        });
+    Some((thread_id, name))
+}
+
+/// The capturebot ID a note of `msg` gets. Message IDs only count up within
+/// a chat, so outside of private chats they're qualified by the chat.
+pub fn message_capturebot_id(msg: &Message) -> String {
+    if msg.chat.is_private() {
+        msg.id.to_string()
+    } else {
+        format!("{}:{}", msg.chat.id, msg.id)
+    }
+}
+
+/// `text` without the `/capture` command or leading mention of the bot
+/// that got it captured.
+fn capture_text(text: &str, bot_username: Option<&str>) -> String {
+    if let Some(rest) = strip_command(text, CAPTURE_COMMAND, bot_username) {
+        return rest.to_string();
+    }
+    if let Some(bot) = bot_username
+        && let Some(mention) = text.get(..bot.len() + 1)
+        && mention.eq_ignore_ascii_case(&format!("@{bot}"))
+    {
+        return text[mention.len()..].trim_start().to_string();
+    }
+    text.to_string()
+}
+
+impl ContextualFrom<Message, &HashMap<String, CapturebotNote>, &CapturebotConfig> for Capture {
+    type Error = std::io::Error;
+    fn contextual_from(
+        msg: Message,
+        notes: &HashMap<String, CapturebotNote>,
+        config: &CapturebotConfig,
+    ) -> Result<Capture, Self::Error> {
+        let bot = config.bot_username.as_deref();
+        let private = msg.chat.is_private();
+        let mut source = &msg;
+        let mut text = capture_text(msg.text().unwrap_or_default(), bot);
+        // a bare /capture in reply to a message captures that message
+        if text.is_empty()
+            && let Some(replied) = replied_to(&msg)
+            && let Some(replied_text) = replied.text()
+        {
+            source = replied;
+            text = capture_text(replied_text, bot);
+        }
+        let mut refs: Vec<String> = source
+            .parse_entities()
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|m| match m.kind() {
+                MessageEntityKind::TextLink { url } => Some(url.to_string()),
+                MessageEntityKind::Url => Some(m.text().to_string()),
+                _ => None,
+            })
+            .collect();
+        let mut properties = Vec::new();
+        let chat_title = msg.chat.title().unwrap_or_default().to_string();
+        if msg.chat.is_channel() {
+            properties.push((CAPTUREBOT_CHANNEL_PROPERTY.to_string(), chat_title));
+            if let Some(signature) = msg.author_signature() {
+                properties.push((CAPTUREBOT_AUTHOR_PROPERTY.to_string(), signature.to_string()));
+            }
+            if let Some(url) = msg.url() {
+                refs.push(url.to_string());
+            }
+        } else if !private {
+            properties.push((CAPTUREBOT_GROUP_PROPERTY.to_string(), chat_title));
+            if let Some(author) = &source.from {
+                let author = match &author.username {
+                    Some(username) => format!("{} (@{username})", author.full_name()),
+                    None => author.full_name(),
+                };
+                properties.push((CAPTUREBOT_AUTHOR_PROPERTY.to_string(), author));
+            }
+            if let Some((thread_id, Some(name))) = message_topic(&msg, notes) {
+                properties.push((CAPTUREBOT_TOPIC_PROPERTY.to_string(), format!("{thread_id} {name}")));
+            }
+        }
+        Ok(Capture {
+            date: source.date,
+            text,
+            refs,
+            tags: config.capture_tags.clone(),
+            capturebot_id: message_capturebot_id(source),
+            capturebot_parent: replied_to(source).map(message_capturebot_id),
+            chat: msg.chat.id.to_string(),
+            properties,
+            org_id: None,
+        })
+    }
+}
+
+impl ContextualFrom<Message, &HashMap<String, CapturebotNote>, &CapturebotConfig>
+    for CapturebotNote
+{
+    type Error = std::io::Error;
+    fn contextual_from(
+        msg: Message,
+        notes: &HashMap<String, CapturebotNote>,
+        config: &CapturebotConfig,
+    ) -> Result<CapturebotNote, Self::Error> {
+        let capture = Capture::contextual_from(msg, notes, config)?;
+        CapturebotNote::contextual_from(capture, notes, config)
+    }
+}
+
+// keeps the summary well under telegram's message length limit
+const MAX_REPORTED_FAILURES: usize = 20;
+
+/// A file under one of the note directories that could not be read or parsed.
+#[derive(Debug)]
+pub struct LoadFailure {
+    pub path: PathBuf,
+    pub error: std::io::Error,
+}
+
+/// Outcome of loading the note directories: how many notes made it into the
+/// map, how many files were served from the index cache, and which files were
+/// skipped along the way.
+#[derive(Debug, Default)]
+pub struct LoadReport {
+    pub loaded: usize,
+    pub from_cache: usize,
+    pub failures: Vec<LoadFailure>,
+}
+
+impl LoadReport {
+    pub fn is_clean(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    /// A short human-readable summary, suitable for sending over Telegram.
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "capturebot loaded {} notes ({} files from the index cache), {} files failed to load",
+            self.loaded,
+            self.from_cache,
+            self.failures.len()
+        );
+        for failure in self.failures.iter().take(MAX_REPORTED_FAILURES) {
+            summary.push_str(&format!("\n- {}: {}", failure.path.display(), failure.error));
+        }
+        if self.failures.len() > MAX_REPORTED_FAILURES {
+            summary.push_str(&format!(
+                "\n... and {} more",
+                self.failures.len() - MAX_REPORTED_FAILURES
+            ));
+        }
+        summary
+    }
+}
+
+async fn load_file(
+    path: &Path,
+    notes: &HashMap<String, CapturebotNote>,
+    config: &CapturebotConfig,
+) -> Result<Vec<CapturebotNote>, std::io::Error> {
+    let s: String = tokio::fs::read_to_string(path).await?;
+    let doc: Document<'_> = parse_file(&s, Some(path)).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("failed to parse contents of file {:?}: {:?}", path, e),
+        )
+    })?;
+    let mut file_notes = Vec::new();
+    if let Ok(note) = CapturebotNote::contextual_from(&doc, notes, config) {
+        file_notes.push(note);
+    } else {
+        eprintln!("failed to create CapturebotNote for {:?}", path);
+    }
+    let filetags = front_matter(&doc)
+        .get("filetags")
+        .map_or(Vec::new(), |t| split_tags(t));
+    for heading in doc.children.iter() {
+        load_heading(heading, path, &mut Vec::new(), &filetags, notes, config, &mut file_notes);
+    }
+    Ok(file_notes)
+}
+
+fn load_heading(
+    heading: &Heading,
+    path: &Path,
+    outline_path: &mut Vec<String>,
+    inherited_tags: &[String],
+    notes: &HashMap<String, CapturebotNote>,
+    config: &CapturebotConfig,
+    file_notes: &mut Vec<CapturebotNote>,
+) {
+    outline_path.push(heading_title(heading));
+    if let Ok(mut note) = CapturebotNote::contextual_from(heading, notes, config) {
+        note.path = path.to_path_buf();
+        note.outline_path = outline_path.clone();
+        note.inherited_tags = inherited_tags.to_vec();
+        file_notes.push(note);
+    }
+    let mut child_tags = inherited_tags.to_vec();
+    child_tags.extend(heading.tags.iter().map(|t| t.to_string()));
+    for child in heading.children.iter() {
+        if let DocumentElement::Heading(child) = child {
+            load_heading(child, path, outline_path, &child_tags, notes, config, file_notes);
+        }
+    }
+    outline_path.pop();
+}
+
+fn find_heading<'a, 's>(heading: &'a Heading<'s>, org_id: &str) -> Option<&'a Heading<'s>> {
+    if heading
+        .get_additional_properties()
+        .any(|p| p.property_name == "ID" && p.value == Some(org_id))
+    {
+        return Some(heading);
+    }
+    heading.children.iter().find_map(|c| match c {
+        DocumentElement::Heading(child) => find_heading(child, org_id),
+        _ => None,
+    })
+}
+
+/// The byte range `note` occupies in `source`, the contents of `note.path`.
+/// File-level notes span the whole file; heading notes span their subtree,
+/// which is found by org ID so that it survives refiling within the file.
+pub fn note_span(source: &str, note: &CapturebotNote) -> Option<Range<usize>> {
+    if !note.is_heading() {
+        return Some(0..source.len());
+    }
+    heading_span(source, &note.id)
+}
+
+/// The byte range of the subtree of the heading with ID `org_id` in `source`.
+pub fn heading_span(source: &str, org_id: &str) -> Option<Range<usize>> {
+    let doc = parse_file(source, None::<&Path>).ok()?;
+    let heading = doc.children.iter().find_map(|h| find_heading(h, org_id))?;
+    let start = heading.source.as_ptr() as usize - source.as_ptr() as usize;
+    Some(start..start + heading.source.len())
+}
+
+/// Reads the org source of `note` back from disk, whether it's a whole file
+/// or a heading inside one.
+pub async fn read_note_source(note: &CapturebotNote) -> Result<String, std::io::Error> {
+    let source = fs::read_to_string(&note.path).await?;
+    let span = note_span(&source, note).ok_or(Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("couldn't find heading {} in {}", note.id, note.path.display()),
+    ))?;
+    Ok(source[span].to_string())
+}
+
 async fn load_from_dir(
     root_dir: PathBuf,
     notes: &mut HashMap<String, CapturebotNote>,
     config: &CapturebotConfig,
+    report: &mut LoadReport,
+    cache: Option<(&NoteIndexCache, &mut NoteIndexCache)>,
 ) -> Result<(), std::io::Error> {
+    let mut cache = cache;
     let direntries = WalkDir::new(root_dir.as_path());
     for direntry in direntries.into_iter().filter_map(|d| {
         if let Some(entry) = d.ok()
@@ -211,71 +846,508 @@ async fn load_from_dir(
             None
         }
     }) {
-        println!("{}", direntry.path().to_string_lossy());
-        let s: String = tokio::fs::read_to_string(direntry.path())
-            .await
-            .inspect_err(|e| eprintln!("read_to_string failed: {:?}", e))?;
-        let doc: Document<'_> = parse_file(&s, Some(direntry.path())).map_err(|e| {
-            std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("failed to parse contents of file {:?}: {:?}", direntry, e),
-            )
-        })?;
-        if let Ok(note) = CapturebotNote::contextual_from(&doc, notes, config) {
-            notes.entry(note.capturebot_id.to_string()).or_insert(note);
-        } else {
-            eprintln!("failed to create CapturebotNote for {:?}", direntry.path(),);
-        }
-        for heading in doc
-            .children
-            .iter()
+        let path = direntry.path();
+        let metadata = direntry.metadata().map_err(std::io::Error::other);
+        if let Some((previous, next)) = cache.as_mut()
+            && let Ok(metadata) = &metadata
+            && let Some(cached) = previous.lookup(path, metadata)
         {
-	    if let Ok(note) = CapturebotNote::contextual_from(heading, notes, config) {
-		notes
-                    .entry(note.capturebot_id.to_string())
-                    .or_insert(note);
-	    }
+            report.from_cache += 1;
+            next.record(path, metadata, cached.to_vec());
+            for note in cached {
+                if !notes.contains_key(&note.capturebot_id) {
+                    notes.insert(note.capturebot_id.clone(), note.clone());
+                    report.loaded += 1;
+                }
+            }
+            continue;
+        }
+        log::debug!("loading {}", path.display());
+        match load_file(path, notes, config).await {
+            Ok(mut file_notes) => {
+                if let Some((_, next)) = cache.as_mut() {
+                    for note in file_notes.iter_mut() {
+                        note.body = String::new();
+                    }
+                    if let Ok(metadata) = &metadata {
+                        next.record(path, metadata, file_notes.clone());
+                    }
+                }
+                for note in file_notes {
+                    if !notes.contains_key(&note.capturebot_id) {
+                        notes.insert(note.capturebot_id.clone(), note);
+                        report.loaded += 1;
+                    }
+                }
+            }
+            Err(error) if config.tolerant_load => {
+                log::warn!("skipping {}: {}", path.display(), error);
+                report.failures.push(LoadFailure {
+                    path: path.to_path_buf(),
+                    error,
+                });
+            }
+            Err(error) => return Err(error),
         }
     }
     Ok(())
 }
 
+/// Loads every capturebot note under `read_dir` and `save_dir` into `notes`.
+///
+/// With `tolerant_load` set, files that can't be read or parsed are recorded
+/// in the returned report instead of aborting the load. With `index_cache`
+/// set, files whose mtime and size haven't changed since the last load are
+/// taken from the cache instead of being parsed again. With `fulltext_index`
+/// set, the notes that changed since they were last indexed are indexed
+/// again.
 pub async fn load_notes(
-    mut notes: &mut HashMap<String, CapturebotNote>,
+    notes: &mut HashMap<String, CapturebotNote>,
+    config: &CapturebotConfig,
+) -> Result<LoadReport, std::io::Error> {
+    let mut report = LoadReport::default();
+    match &config.index_cache {
+        Some(cache_path) => {
+            let previous = NoteIndexCache::load(cache_path).await;
+            let mut next = NoteIndexCache::default();
+            for dir in [&config.read_dir, &config.save_dir] {
+                load_from_dir(dir.clone(), notes, config, &mut report, Some((&previous, &mut next)))
+                    .await?;
+            }
+            next.save(cache_path).await?;
+        }
+        None => {
+            for dir in [&config.read_dir, &config.save_dir] {
+                load_from_dir(dir.clone(), notes, config, &mut report, None).await?;
+            }
+        }
+    }
+    fulltext::sync(notes, config).await;
+    Ok(report)
+}
+
+/// Re-reads a single org file, replacing whatever notes it held before.
+pub async fn reload_file(
+    path: &Path,
+    notes: &mut HashMap<String, CapturebotNote>,
     config: &CapturebotConfig,
 ) -> Result<(), std::io::Error> {
-    load_from_dir(config.read_dir.clone(), &mut notes, config).await?;
-    load_from_dir(config.save_dir.clone(), &mut notes, config).await?;
+    let file_notes = load_file(path, notes, config).await?;
+    let removed: Vec<String> = notes
+        .values()
+        .filter(|n| n.path == path && !file_notes.iter().any(|f| f.capturebot_id == n.capturebot_id))
+        .map(|n| n.capturebot_id.clone())
+        .collect();
+    fulltext::update(&file_notes.iter().collect::<Vec<_>>(), &removed, config).await;
+    forget_file(path, notes);
+    for mut note in file_notes {
+        if config.index_cache.is_some() {
+            note.body = String::new();
+        }
+        notes.insert(note.capturebot_id.clone(), note);
+    }
     Ok(())
 }
 
+/// Drops every note that was loaded from `path`.
+pub fn forget_file(path: &Path, notes: &mut HashMap<String, CapturebotNote>) {
+    notes.retain(|_, note| note.path != path);
+}
+
+/// Throws away the index cache and the full-text index and rebuilds them by
+/// parsing every note file.
+pub async fn reindex(config: &CapturebotConfig) -> Result<LoadReport, std::io::Error> {
+    if config.index_cache.is_none() && config.fulltext_index.is_none() {
+        return Err(Error::new(
+            std::io::ErrorKind::NotFound,
+            "no index configured, set CAPTUREBOT_INDEX_CACHE or CAPTUREBOT_FULLTEXT_INDEX",
+        ));
+    }
+    if let Some(cache_path) = &config.index_cache {
+        match fs::remove_file(cache_path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    if let Some(index_dir) = &config.fulltext_index {
+        match fs::remove_dir_all(index_dir).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    load_notes(&mut HashMap::new(), config).await
+}
+
+/// Each authorized user's notes, by Telegram user ID, kept apart so that one
+/// person's captures never land in another's notes.
+pub type UserNotes = HashMap<u64, Arc<Mutex<HashMap<String, CapturebotNote>>>>;
+
+/// Loads the notes of everyone in `config`, with a load report per user.
+pub async fn load_user_notes(
+    config: &CapturebotConfig,
+) -> Result<(UserNotes, Vec<(u64, LoadReport)>), std::io::Error> {
+    let mut user_notes = UserNotes::new();
+    let mut reports = Vec::new();
+    for user in config.all_users() {
+        let mut notes = HashMap::new();
+        reports.push((user.user_id, load_notes(&mut notes, user).await?));
+        user_notes.insert(user.user_id, Arc::new(Mutex::new(notes)));
+    }
+    Ok((user_notes, reports))
+}
+
+// whether switching from `old` to `new` means notes have to be reloaded
+fn dirs_changed(old: Option<&CapturebotConfig>, new: &CapturebotConfig) -> bool {
+    old.is_none_or(|old| {
+        old.read_dir != new.read_dir
+            || old.save_dir != new.save_dir
+            || old.index_cache != new.index_cache
+    })
+}
+
+/// Re-reads the config at `path` and swaps it in for the messages handled from
+/// now on. Users whose note directories changed, and new users, get their
+/// notes reloaded, and their load reports are returned. A config that doesn't
+/// validate, or whose directories don't load, is rejected and the old one
+/// stays in effect.
+pub async fn reload_config(
+    path: Option<&Path>,
+    config: &RwLock<CapturebotConfig>,
+    notes: &RwLock<UserNotes>,
+) -> Result<Vec<(u64, LoadReport)>, ConfigError> {
+    let mut new_config = CapturebotConfig::load(path)?;
+    let old_config = config.read().await.clone();
+    if new_config.bot_username.is_none() {
+        new_config.bot_username = old_config.bot_username.clone();
+    }
+    let mut reports = Vec::new();
+    let mut reloaded = UserNotes::new();
+    for user in new_config.all_users() {
+        if !dirs_changed(old_config.for_user(user.user_id), user) {
+            continue;
+        }
+        let mut loaded = HashMap::new();
+        let report = load_notes(&mut loaded, user).await.map_err(|e| {
+            ConfigError(vec![format!(
+                "couldn't load notes for user {} from the new directories: {e}",
+                user.user_id
+            )])
+        })?;
+        reports.push((user.user_id, report));
+        reloaded.insert(user.user_id, Arc::new(Mutex::new(loaded)));
+    }
+    // swap both while holding the notes lock, so no capture sees new notes
+    // with the old config
+    let mut notes_guard = notes.write().await;
+    notes_guard.retain(|user_id, _| new_config.for_user(*user_id).is_some());
+    notes_guard.extend(reloaded);
+    *config.write().await = new_config;
+    Ok(reports)
+}
+
 pub trait ValidMessage<C>: Sized {
     fn is_valid_msg(msg: Self, config: C) -> bool;
 }
 
 impl ValidMessage<&CapturebotConfig> for Message {
     fn is_valid_msg(msg: Self, config: &CapturebotConfig) -> bool {
-        msg.text().is_some() && msg.clone().from.is_some_and(|u| u.id.0 == config.user_id)
+        if msg.text().is_none() {
+            return false;
+        }
+        // every post in a configured channel is captured
+        if msg.chat.is_channel() {
+            return config.chat(msg.chat.id.0).is_some();
+        }
+        let Some(from) = &msg.from else {
+            return false;
+        };
+        if msg.chat.is_private() {
+            config.for_user(from.id.0).is_some()
+        } else {
+            config.chat(msg.chat.id.0).is_some()
+                && is_group_capture(&msg, config.bot_username.as_deref())
+        }
     }
 }
 
+/// Writes a freshly made note to disk. Heading notes are added to their file,
+/// under their parent if the parent is in the same file and they're meant to
+/// nest, and new daily files get an ID and title; file notes get a new file,
+/// and if their path is already taken they are saved under a suffixed name
+/// instead and `note.path` is updated to match.
+pub async fn save_note(
+    note: &mut CapturebotNote,
+    notes: &HashMap<String, CapturebotNote>,
+    config: &CapturebotConfig,
+) -> Result<(), std::io::Error> {
+    if !note.is_heading() {
+        note.path = write::write_new_file(&note.path, &note.body).await?;
+        return Ok(());
+    }
+    let parent = note
+        ._capturebot_parent
+        .as_ref()
+        .and_then(|rt| notes.get(rt))
+        .filter(|p| {
+            p.path == note.path
+                && note.outline_path.len() > p.outline_path.len()
+                && note.outline_path.starts_with(&p.outline_path)
+        });
+    if let Some(parent) = parent {
+        match inbox::insert_heading(&note.path, &note.body, &parent.id).await {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                log::warn!("{e}, appending instead");
+            }
+            inserted => return inserted,
+        }
+    }
+    let header = match &config.capture_target {
+        CaptureTarget::Dailies(_) => format!(
+            ":PROPERTIES:\n:ID: {}\n:END:\n#+title: {}\n",
+            gen_uuid(true),
+            note.path.file_stem().unwrap_or_default().to_string_lossy()
+        ),
+        _ => String::new(),
+    };
+    inbox::append_heading(&note.path, &note.body, &header).await
+}
+
+/// Saves a note of `msg`, returning it, or nothing if the message was
+/// captured already.
 pub async fn add_note(
     msg: Message,
     notes: &mut HashMap<String, CapturebotNote>,
     config: &CapturebotConfig,
+) -> Result<Option<CapturebotNote>, std::io::Error> {
+    let mut new_note = CapturebotNote::contextual_from(msg, notes, config)?;
+    if notes.contains_key(&new_note.capturebot_id) {
+        println!("skipping {:?} : {:?}", new_note.capturebot_id, new_note.title);
+        Ok(None)
+    } else {
+        println!("noting {:?} : {:?}", new_note.capturebot_id, new_note.title);
+        save_note(&mut new_note, notes, config).await?;
+        if new_note.is_heading() {
+            inherit_tags(&mut new_note, notes, config).await;
+        }
+        fulltext::index_capture(&new_note, config).await;
+        notes.insert(new_note.capturebot_id.clone(), new_note.clone());
+        Ok(Some(new_note))
+    }
+}
+
+/// Picks up the tags a heading note inherits from the file and headings it
+/// was saved under, by reading back the file it was saved to.
+async fn inherit_tags(
+    note: &mut CapturebotNote,
+    notes: &HashMap<String, CapturebotNote>,
+    config: &CapturebotConfig,
+) {
+    match load_file(&note.path, notes, config).await {
+        Ok(file_notes) => {
+            if let Some(saved) = file_notes.into_iter().find(|n| n.capturebot_id == note.capturebot_id) {
+                note.inherited_tags = saved.inherited_tags;
+            }
+        }
+        Err(e) => log::warn!("couldn't read back {}: {e}", note.path.display()),
+    }
+}
+
+/// Brings the note made from an edited message up to date, keeping its org
+/// ID and where it lives so links to it keep working, and whatever was added
+/// to it since it was captured. Messages that weren't captured before are
+/// added as new notes.
+pub async fn update_note(
+    msg: Message,
+    notes: &mut HashMap<String, CapturebotNote>,
+    config: &CapturebotConfig,
 ) -> Result<(), std::io::Error> {
-    if notes.contains_key(&msg.id.to_string()) {
-        println!("skipping {:?} : {:?}", msg.id, msg.text());
-        Ok(())
+    let mut capture = Capture::contextual_from(msg.clone(), notes, config)?;
+    let Some(old) = notes.remove(&capture.capturebot_id) else {
+        return add_note(msg, notes, config).await.map(|_| ());
+    };
+    capture.org_id = Some(old.id.clone());
+    let fresh = CapturebotNote::contextual_from(capture, notes, config);
+    notes.insert(old.capturebot_id.clone(), old);
+    let fresh = fresh?;
+    let note = edit_note(&fresh.capturebot_id, notes, |note, source| {
+        edit::replace_text(source, note.is_heading(), &fresh.body)
+    })
+    .await?;
+    note.title = fresh.title;
+    note.refs = fresh.refs;
+    note.tags = edit::merged_tags(&note.tags, &fresh.tags);
+    fulltext::update(&[&*note], &[], config).await;
+    Ok(())
+}
+
+fn missing_note(capturebot_id: &str) -> Error {
+    Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("there's no note of message {capturebot_id} anymore"),
+    )
+}
+
+/// Rewrites the saved note of message `capturebot_id` in place with `edit`,
+/// which gets the note and its org source and returns the new source.
+async fn edit_note<'a>(
+    capturebot_id: &str,
+    notes: &'a mut HashMap<String, CapturebotNote>,
+    edit: impl FnOnce(&CapturebotNote, &str) -> String,
+) -> Result<&'a mut CapturebotNote, std::io::Error> {
+    let note = notes
+        .get_mut(capturebot_id)
+        .ok_or_else(|| missing_note(capturebot_id))?;
+    let source = if note.is_heading() {
+        inbox::edit_heading(&note.path, &note.id, |subtree| edit(note, subtree)).await?
     } else {
-        println!("noting {:?} : {:?}", msg.id, msg.text());
-        let new_note = CapturebotNote::contextual_from(msg, notes, config)?;
-        fs::write(Path::new(&new_note.path), new_note.body.clone())
-            .await
-            .and_then(|_| {
-                notes.insert(new_note.capturebot_id.clone(), new_note);
-                Ok(())
-            })?;
-        Ok(())
+        let source = edit(note, &fs::read_to_string(&note.path).await?);
+        write::replace_file(&note.path, &source).await?;
+        source
+    };
+    // notes loaded through the index cache don't keep their source around
+    if !note.body.is_empty() {
+        note.body = source;
+    }
+    Ok(note)
+}
+
+/// Adds `tags` to the note of message `capturebot_id`.
+pub async fn tag_note(
+    capturebot_id: &str,
+    tags: &[String],
+    notes: &mut HashMap<String, CapturebotNote>,
+) -> Result<CapturebotNote, std::io::Error> {
+    let note = edit_note(capturebot_id, notes, |note, source| {
+        edit::add_tags(source, note.is_heading(), tags)
+    })
+    .await?;
+    note.tags = edit::merged_tags(&note.tags, tags);
+    Ok(note.clone())
+}
+
+/// Marks the note of message `capturebot_id` TODO.
+pub async fn todo_note(
+    capturebot_id: &str,
+    notes: &mut HashMap<String, CapturebotNote>,
+) -> Result<CapturebotNote, std::io::Error> {
+    let note = edit_note(capturebot_id, notes, |note, source| {
+        edit::make_todo(source, note.is_heading(), &note.title)
+    })
+    .await?;
+    Ok(note.clone())
+}
+
+/// Links the note of message `capturebot_id` to the note of message
+/// `target_id`.
+pub async fn link_note(
+    capturebot_id: &str,
+    target_id: &str,
+    notes: &mut HashMap<String, CapturebotNote>,
+) -> Result<CapturebotNote, std::io::Error> {
+    if capturebot_id == target_id {
+        return Err(Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "a note can't link to itself",
+        ));
+    }
+    let link = notes
+        .get(target_id)
+        .ok_or_else(|| missing_note(target_id))?
+        .org_link();
+    let note = edit_note(capturebot_id, notes, |note, source| {
+        edit::add_link(source, note.is_heading(), &link)
+    })
+    .await?;
+    Ok(note.clone())
+}
+
+/// A note that was deleted, where it went, and the notes left linking to it.
+#[derive(Debug)]
+pub struct DeletedNote {
+    pub note: CapturebotNote,
+    pub trash_path: PathBuf,
+    pub linked_from: Vec<CapturebotNote>,
+}
+
+/// Deletes the note of message `capturebot_id`, its file or its heading,
+/// moving it to `trash_dir` and dropping it from `notes`. Headings nested
+/// under a deleted heading go with it.
+pub async fn delete_note(
+    capturebot_id: &str,
+    notes: &mut HashMap<String, CapturebotNote>,
+    config: &CapturebotConfig,
+) -> Result<DeletedNote, std::io::Error> {
+    let note = notes
+        .get(capturebot_id)
+        .ok_or_else(|| missing_note(capturebot_id))?
+        .clone();
+    // into the trash first, so nothing is lost if that fails
+    let source = read_note_source(&note).await?;
+    let trash_name = if note.is_heading() {
+        let slug = slug::slug(&note.title, config.slug_style);
+        PathBuf::from(format!("{}.org", if slug.is_empty() { &note.id } else { &slug }))
+    } else {
+        PathBuf::from(note.path.file_name().unwrap_or_default())
+    };
+    let trash_path = write::write_new_file(&config.trash_dir.join(trash_name), &source).await?;
+    let deleted: Vec<String> = notes
+        .values()
+        .filter(|n| {
+            n.path == note.path && (!note.is_heading() || n.outline_path.starts_with(&note.outline_path))
+        })
+        .map(|n| n.capturebot_id.clone())
+        .collect();
+    if note.is_heading() {
+        inbox::edit_heading(&note.path, &note.id, |_| String::new()).await?;
+    } else {
+        fs::remove_file(&note.path).await?;
+    }
+    notes.retain(|id, _| !deleted.contains(id));
+    fulltext::update(&[], &deleted, config).await;
+    let linked_from = notes_linking_to(&note, notes).await;
+    Ok(DeletedNote {
+        note,
+        trash_path,
+        linked_from,
+    })
+}
+
+/// The notes in `notes` with a link to `target`.
+pub async fn notes_linking_to(
+    target: &CapturebotNote,
+    notes: &HashMap<String, CapturebotNote>,
+) -> Vec<CapturebotNote> {
+    let link = target.link_target();
+    let mut linking = Vec::new();
+    for note in notes.values() {
+        let source = if note.body.is_empty() {
+            read_note_source(note).await.unwrap_or_default()
+        } else {
+            note.body.clone()
+        };
+        if source.contains(&link) {
+            linking.push(note.clone());
+        }
+    }
+    linking
+}
+
+/// The notes `query` picks out: the one with that org ID or message ID, or
+/// else those with `query` in their title, ignoring case.
+pub fn find_notes<'a>(
+    query: &str,
+    notes: &'a HashMap<String, CapturebotNote>,
+) -> Vec<&'a CapturebotNote> {
+    let query = query.trim();
+    if let Some(note) = notes
+        .values()
+        .find(|n| n.id == query || n.capturebot_id == query)
+    {
+        return vec![note];
     }
+    let query = query.to_lowercase();
+    notes
+        .values()
+        .filter(|n| n.title.to_lowercase().contains(&query))
+        .collect()
 }
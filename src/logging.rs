@@ -0,0 +1,64 @@
+use std::sync::RwLock;
+
+use log::{Log, Metadata, Record};
+
+static SECRETS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+static REDACTED: &str = "<redacted>";
+
+/// Wraps the usual logger, blanking out secrets that would otherwise end up
+/// in log lines, like the bot token in request URLs.
+struct RedactingLogger(Box<dyn Log>);
+
+impl Log for RedactingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        let secrets = SECRETS.read().unwrap_or_else(|e| e.into_inner());
+        let mut message = record.args().to_string();
+        if !secrets.iter().any(|s| message.contains(s.as_str())) {
+            return self.0.log(record);
+        }
+        for secret in secrets.iter() {
+            message = message.replace(secret.as_str(), REDACTED);
+        }
+        self.0.log(
+            &Record::builder()
+                .args(format_args!("{message}"))
+                .metadata(record.metadata().clone())
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .build(),
+        );
+    }
+
+    fn flush(&self) {
+        self.0.flush()
+    }
+}
+
+/// Sets up `pretty_env_logger`, configured by `RUST_LOG` as usual, behind
+/// the redacting logger.
+pub fn init() {
+    let mut builder = pretty_env_logger::formatted_builder();
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    let logger = builder.build();
+    log::set_max_level(logger.filter());
+    log::set_boxed_logger(Box::new(RedactingLogger(Box::new(logger))))
+        .expect("the logger should only be set up once");
+}
+
+/// Keeps `secret` out of everything logged from now on.
+pub fn redact(secret: &str) {
+    if secret.is_empty() {
+        return;
+    }
+    let mut secrets = SECRETS.write().unwrap_or_else(|e| e.into_inner());
+    if !secrets.iter().any(|s| s == secret) {
+        secrets.push(secret.to_string());
+    }
+}
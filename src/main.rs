@@ -1,41 +1,1066 @@
-use capturebot::{add_note, load_notes, is_valid_msg, CapturebotConfig};
+use capturebot::access::{AccessLog, AccessStatus, Sighting};
+use capturebot::ack::{Ack, Acks, Prompt};
+use capturebot::logging;
+use capturebot::search::{search, Query};
+use capturebot::watch::{watch_config, watch_notes};
+use capturebot::{
+    add_note, delete_note, find_notes, link_note, load_notes, load_user_notes,
+    message_capturebot_id, message_topic, read_note_source, reindex, reload_config, strip_command,
+    tag_note, take_config_arg, todo_note, update_note, CapturebotConfig, CapturebotNote,
+    DeletedNote, LoadReport, UserNotes, ValidMessage,
+};
+use notify::RecommendedWatcher;
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::Arc;
-use teloxide::types::Message;
-use teloxide::{RequestError, prelude::*};
-use tokio::sync::Mutex;
+use std::time::Duration;
+use chrono::Utc;
+use teloxide::types::{
+    ForceReply, InlineKeyboardButton, InlineKeyboardMarkup, InlineQueryResult,
+    InlineQueryResultArticle, InputMessageContent, InputMessageContentText, Message, MessageId,
+    ReplyParameters, User,
+};
+use teloxide::{ApiError, RequestError, prelude::*};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, Mutex, RwLock};
 
+// editors tend to touch a file several times per save
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(300);
+
+static REFUSAL: &str =
+    "Sorry, this bot only saves notes for the people it's set up for, so I can't keep this.";
+static WELCOME: &str = "You've been let in, so from now on I'll save what you send me.";
+
+// callback data of the buttons the owner gets about strangers
+static ACCESS_ALLOW: &str = "access:allow:";
+static ACCESS_BLOCK: &str = "access:block:";
+
+// callback data of the buttons under acknowledgements
+static NOTE_TAG: &str = "note:tag";
+static NOTE_TODO: &str = "note:todo";
+static NOTE_READ_LATER: &str = "note:readlater";
+static NOTE_UNDO: &str = "note:undo";
+static NOTE_LINK: &str = "note:link";
+
+static UNDO_COMMAND: &str = "/undo";
+static DELETE_COMMAND: &str = "/delete";
+static SEARCH_COMMAND: &str = "/search";
+static SEARCH_USAGE: &str = "Search with /search and some words or \"quoted phrases\", narrowed \
+     down with tag:name, -tag:name, url:part-of-a-link, before:YYYY-MM-DD and after:YYYY-MM-DD.";
+static SEARCH_ELSEWHERE: &str = "Search me in our private chat, so your notes stay between us.";
+
+// callback data of the buttons under search results, followed by the
+// capturebot ID of the note to send
+static SEARCH_OPEN: &str = "search:open:";
+const MAX_SEARCH_RESULTS: usize = 5;
+const MAX_CLI_SEARCH_RESULTS: usize = 50;
+// telegram's limit, in characters
+const MAX_MESSAGE_LENGTH: usize = 4096;
+
+static READ_LATER_TAG: &str = "readlater";
+// telegram takes up to 50
+const MAX_INLINE_RESULTS: usize = 20;
+// so that a list of candidates fits in a message
+const MAX_LISTED_NOTES: usize = 5;
+
+async fn report_load(bot: &Bot, config: &CapturebotConfig, report: &LoadReport) {
+    log::info!("loaded {} notes for user {}", report.loaded, config.user_id);
+    if !report.is_clean() {
+        log::warn!("{}", report.summary());
+        if config.report_load_errors {
+            bot.send_message(ChatId(config.user_id as i64), report.summary())
+                .await
+                .inspect_err(|e| log::error!("couldn't send load report: {e}"))
+                .ok();
+        }
+    }
+}
+
+// one watcher per user, following that user's directories
+async fn watch_user_notes(config: &CapturebotConfig, notes: &RwLock<UserNotes>) -> Vec<RecommendedWatcher> {
+    let notes = notes.read().await;
+    config
+        .all_users()
+        .filter_map(|user| {
+            let user_notes = notes.get(&user.user_id)?.clone();
+            watch_notes(user_notes, user)
+                .inspect_err(|e| log::error!("couldn't watch note directories of user {}: {e}", user.user_id))
+                .ok()
+        })
+        .collect()
+}
+
+/// Reloads the config whenever something arrives on `changes`, restarting
+/// the note watchers so they follow the new directories.
+async fn reload_on_change(
+    bot: Bot,
+    config_path: Option<PathBuf>,
+    config: Arc<RwLock<CapturebotConfig>>,
+    notes: Arc<RwLock<UserNotes>>,
+    mut changes: mpsc::UnboundedReceiver<()>,
+) {
+    let mut _watchers = watch_user_notes(&*config.read().await, &notes).await;
+    while changes.recv().await.is_some() {
+        tokio::time::sleep(RELOAD_DEBOUNCE).await;
+        while changes.try_recv().is_ok() {}
+
+        match reload_config(config_path.as_deref(), &config, &notes).await {
+            Ok(reports) => {
+                log::info!("reloaded config");
+                let config = config.read().await.clone();
+                if let Some(token) = &config.bot_token {
+                    // the bot keeps its token, but a new one shouldn't leak either
+                    logging::redact(token.expose());
+                }
+                for (user_id, report) in reports {
+                    if let Some(user) = config.for_user(user_id) {
+                        report_load(&bot, user, &report).await;
+                    }
+                }
+                _watchers = watch_user_notes(&config, &notes).await;
+            }
+            Err(e) => {
+                log::error!("keeping the old config: {e}");
+                let user_id = config.read().await.user_id;
+                bot.send_message(ChatId(user_id as i64), format!("Keeping the old config, the new one is {e}"))
+                    .await
+                    .inspect_err(|e| log::error!("couldn't send config error: {e}"))
+                    .ok();
+            }
+        }
+    }
+}
+
+/// What every handler gets to work with.
+#[derive(Clone)]
+struct App {
+    config: Arc<RwLock<CapturebotConfig>>,
+    notes: Arc<RwLock<UserNotes>>,
+    access: Arc<Mutex<AccessLog>>,
+    acks: Arc<Mutex<Acks>>,
+    /// Asks for the config to be reloaded, e.g. after letting someone in.
+    reload: mpsc::UnboundedSender<()>,
+}
+
+/// Whose notes a capture of `msg` goes into, with the settings to save it
+/// with. Group and channel captures go to whoever the chat belongs to.
+async fn capture_destination(
+    app: &App,
+    config: &CapturebotConfig,
+    msg: &Message,
+) -> Option<(CapturebotConfig, Arc<Mutex<HashMap<String, CapturebotNote>>>)> {
+    let mut user = if msg.chat.is_private() {
+        config.for_user(msg.from.as_ref()?.id.0).cloned()
+    } else {
+        config.for_chat(msg.chat.id.0)
+    }?;
+    let notes = app.notes.read().await.get(&user.user_id).cloned()?;
+    // forum topics can have directories and tags of their own
+    if let Some((thread_id, name)) = message_topic(msg, &*notes.lock().await) {
+        user = config.for_topic(msg.chat.id.0, thread_id, name.as_deref())?;
+    }
+    Some((user, notes))
+}
+
+/// Makes the directory a new forum topic's captures go into, so that it's
+/// there before the first of them.
+async fn create_topic_dir(config: &CapturebotConfig, msg: &Message, name: &str) {
+    // the message creating a topic is the root of its thread
+    let thread_id = msg.thread_id.map_or(msg.id.0, |t| t.0.0);
+    let Some(topic) = config.for_topic(msg.chat.id.0, thread_id, Some(name)) else {
+        return;
+    };
+    match tokio::fs::create_dir_all(&topic.save_dir).await {
+        Ok(()) => log::info!("topic {name:?} saves to {}", topic.save_dir.display()),
+        Err(e) => log::error!("couldn't create {} for topic {name:?}: {e}", topic.save_dir.display()),
+    }
+}
+
+async fn handle_message(bot: Bot, app: App, msg: Message) -> ResponseResult<()> {
+    if let Some(from) = &msg.from
+        && app.access.lock().await.is_blocked(from.id.0)
+    {
+        return Ok(());
+    }
+    // read once, so a reload mid-message can't mix two configs
+    let config = app.config.read().await.clone();
+    if let Some(created) = msg.forum_topic_created() {
+        create_topic_dir(&config, &msg, &created.name).await;
+        return Ok(());
+    }
+    let prompt = match msg.reply_to_message() {
+        Some(replied) => app.acks.lock().await.get(replied.chat.id.0, replied.id.0).cloned(),
+        None => None,
+    };
+    if let Some(prompt) = prompt.filter(|p| p.prompt.is_some())
+        && let Some(text) = msg.text()
+    {
+        return answer_prompt(&bot, &app, &config, &msg, text, prompt).await;
+    }
+    if let Some(text) = msg.text()
+        && let Some(from) = &msg.from
+        && let Some(user) = config.for_user(from.id.0)
+    {
+        let bot_username = config.bot_username.as_deref();
+        if strip_command(text, UNDO_COMMAND, bot_username).is_some() {
+            return handle_undo(&bot, &app, user, &msg).await;
+        }
+        if strip_command(text, DELETE_COMMAND, bot_username).is_some() {
+            return handle_delete(&bot, &app, user, &msg).await;
+        }
+        if let Some(terms) = strip_command(text, SEARCH_COMMAND, bot_username) {
+            return handle_search(&bot, &app, user, &msg, terms).await;
+        }
+    }
+    if !Message::is_valid_msg(msg.clone(), &config) {
+        if let Some(from) = &msg.from
+            && let Some(text) = msg.text()
+            && msg.chat.is_private()
+            && config.for_user(from.id.0).is_none()
+        {
+            handle_stranger(&bot, &app, &config, from, text).await?;
+        }
+        return Ok(());
+    }
+    let Some((user, user_notes)) = capture_destination(&app, &config, &msg).await else {
+        return Ok(());
+    };
+    let mut notes_guard = user_notes.lock().await;
+    let added = add_note(msg.clone(), &mut notes_guard, &user).await;
+    drop(notes_guard);
+    acknowledge(&bot, &app, &config, &msg, user.user_id, added).await
+}
+
+fn ack_text(note: &CapturebotNote, done: &[String]) -> String {
+    let file_name = note.path.file_name().unwrap_or_default().to_string_lossy();
+    let mut text = format!("Saved \"{}\"\nFile: {file_name}\nID: {}", note.title, note.id);
+    if !note.tags.is_empty() {
+        text.push_str(&format!("\nTags: :{}:", note.tags.join(":")));
+    }
+    for line in done {
+        text.push_str(&format!("\n{line}"));
+    }
+    text
+}
+
+fn ack_buttons() -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new([
+        vec![
+            InlineKeyboardButton::callback("Tag", NOTE_TAG),
+            InlineKeyboardButton::callback("Make TODO", NOTE_TODO),
+            InlineKeyboardButton::callback("Read later", NOTE_READ_LATER),
+        ],
+        vec![
+            InlineKeyboardButton::callback("Undo", NOTE_UNDO),
+            InlineKeyboardButton::callback("Link to…", NOTE_LINK),
+        ],
+    ])
+}
+
+async fn save_acks(acks: &Acks, config: &CapturebotConfig) {
+    acks.save(&config.acks_path())
+        .await
+        .inspect_err(|e| log::error!("couldn't save acknowledgements: {e}"))
+        .ok();
+}
+
+/// Tells the sender of `msg` whether it was saved, and remembers which note
+/// the acknowledgement is about so that replies to it can refer to the note.
+async fn acknowledge(
+    bot: &Bot,
+    app: &App,
+    config: &CapturebotConfig,
+    msg: &Message,
+    user_id: u64,
+    added: Result<Option<CapturebotNote>, std::io::Error>,
+) -> ResponseResult<()> {
+    let text = match &added {
+        Ok(Some(note)) => ack_text(note, &[]),
+        // captured before, and acknowledged then
+        Ok(None) => return Ok(()),
+        Err(e) => {
+            log::error!("couldn't save message {} from chat {}: {e}", msg.id, msg.chat.id);
+            format!("Couldn't save this: {e}")
+        }
+    };
+    // replying in a channel would post the acknowledgement for everyone
+    let mut request = if msg.chat.is_channel() {
+        bot.send_message(ChatId(user_id as i64), text)
+    } else {
+        bot.send_message(msg.chat.id, text)
+            .reply_parameters(ReplyParameters::new(msg.id))
+    };
+    if matches!(added, Ok(Some(_))) {
+        request = request.reply_markup(ack_buttons());
+    }
+    let sent = request.await?;
+    if let Ok(Some(note)) = added {
+        let mut acks = app.acks.lock().await;
+        acks.record_capture(user_id, note.capturebot_id.clone());
+        acks.insert(sent.chat.id.0, sent.id.0, Ack::new(user_id, note.capturebot_id));
+        save_acks(&acks, config).await;
+    }
+    Ok(())
+}
+
+/// Brings the acknowledgement `ack_id` up to date with its note, adding
+/// `done` to what it says was done to the note.
+async fn update_ack(
+    bot: &Bot,
+    app: &App,
+    config: &CapturebotConfig,
+    chat_id: ChatId,
+    ack_id: MessageId,
+    note: &CapturebotNote,
+    done: Option<String>,
+) -> ResponseResult<()> {
+    let mut acks = app.acks.lock().await;
+    let Some(ack) = acks.get_mut(chat_id.0, ack_id.0) else {
+        return Ok(());
+    };
+    if let Some(done) = done
+        && !ack.done.contains(&done)
+    {
+        ack.done.push(done);
+    }
+    let text = ack_text(note, &ack.done);
+    save_acks(&acks, config).await;
+    drop(acks);
+    match bot
+        .edit_message_text(chat_id, ack_id, text)
+        .reply_markup(ack_buttons())
+        .await
+    {
+        // pressing a button again can leave the note as it was
+        Err(RequestError::Api(ApiError::MessageNotModified)) => Ok(()),
+        edited => edited.map(|_| ()),
+    }
+}
+
+/// Org tags out of a reply like "#reading, to-read": words split on spaces,
+/// commas and colons, without leading `#`s, and only those org allows.
+fn parse_tags(text: &str) -> Vec<String> {
+    text.split([' ', ',', ':', '\n'])
+        .map(|t| t.trim_start_matches('#'))
+        .filter(|t| !t.is_empty() && t.chars().all(|c| c.is_alphanumeric() || "_@#%".contains(c)))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Carries out a button under an acknowledgement on the note it's about.
+/// Tagging and linking need more to go on, so those ask for it.
+async fn handle_note_action(bot: Bot, app: App, q: CallbackQuery) -> ResponseResult<()> {
+    let data = q.data.as_deref().unwrap_or_default();
+    let Some(message) = q.regular_message() else {
+        bot.answer_callback_query(q.id).await?;
+        return Ok(());
+    };
+    let ack = app.acks.lock().await.get(message.chat.id.0, message.id.0).cloned();
+    let Some(ack) = ack else {
+        bot.answer_callback_query(q.id.clone())
+            .text("I've lost track of that note")
+            .await?;
+        return Ok(());
+    };
+    if q.from.id.0 != ack.user_id {
+        bot.answer_callback_query(q.id.clone())
+            .text("Only whoever it was saved for can change this note")
+            .await?;
+        return Ok(());
+    }
+    let config = app.config.read().await.clone();
+    let Some(user_notes) = app.notes.read().await.get(&ack.user_id).cloned() else {
+        bot.answer_callback_query(q.id.clone()).await?;
+        return Ok(());
+    };
+
+    let question = match data {
+        d if d == NOTE_TAG => Some((Prompt::Tag, "Which tags should it get? Reply with them.")),
+        d if d == NOTE_LINK => Some((
+            Prompt::Link,
+            "Which note should it link to? Reply with its title or ID.",
+        )),
+        _ => None,
+    };
+    if let Some((prompt, question)) = question {
+        let asked = bot
+            .send_message(message.chat.id, question)
+            .reply_parameters(ReplyParameters::new(message.id))
+            .reply_markup(ForceReply::new().selective())
+            .await?;
+        let mut acks = app.acks.lock().await;
+        acks.insert(
+            asked.chat.id.0,
+            asked.id.0,
+            Ack {
+                prompt: Some((prompt, message.id.0)),
+                ..Ack::new(ack.user_id, ack.capturebot_id)
+            },
+        );
+        save_acks(&acks, &config).await;
+        drop(acks);
+        bot.answer_callback_query(q.id.clone()).await?;
+        return Ok(());
+    }
+
+    let mut notes = user_notes.lock().await;
+    if data == NOTE_UNDO
+        && let Some(user) = config.for_user(ack.user_id)
+    {
+        let deleted = delete_note(&ack.capturebot_id, &mut notes, user).await;
+        drop(notes);
+        let answer = match deleted {
+            Ok(deleted) => {
+                let mut acks = app.acks.lock().await;
+                acks.forget_capture(ack.user_id, &ack.capturebot_id);
+                save_acks(&acks, &config).await;
+                drop(acks);
+                bot.edit_message_text(message.chat.id, message.id, deletion_report(&deleted))
+                    .await?;
+                "Deleted".to_string()
+            }
+            Err(e) => {
+                log::error!("couldn't delete note {}: {e}", ack.capturebot_id);
+                format!("Couldn't delete it: {e}")
+            }
+        };
+        bot.answer_callback_query(q.id.clone()).text(answer).await?;
+        return Ok(());
+    }
+    let (edited, done) = match data {
+        d if d == NOTE_TODO => (
+            todo_note(&ack.capturebot_id, &mut notes).await,
+            Some("Marked TODO".to_string()),
+        ),
+        d if d == NOTE_READ_LATER => (
+            tag_note(&ack.capturebot_id, &[READ_LATER_TAG.to_string()], &mut notes).await,
+            None,
+        ),
+        _ => {
+            bot.answer_callback_query(q.id.clone()).await?;
+            return Ok(());
+        }
+    };
+    drop(notes);
+    let answer = match edited {
+        Ok(note) => {
+            update_ack(&bot, &app, &config, message.chat.id, message.id, &note, done).await?;
+            "Done".to_string()
+        }
+        Err(e) => {
+            log::error!("couldn't change note {}: {e}", ack.capturebot_id);
+            format!("Couldn't change it: {e}")
+        }
+    };
+    bot.answer_callback_query(q.id.clone()).text(answer).await?;
+    Ok(())
+}
+
+fn deletion_report(deleted: &DeletedNote) -> String {
+    let file_name = |path: &std::path::Path| {
+        path.file_name().unwrap_or_default().to_string_lossy().to_string()
+    };
+    let mut report = format!(
+        "Moved \"{}\" to the trash as {}.",
+        deleted.note.title,
+        file_name(&deleted.trash_path)
+    );
+    if !deleted.linked_from.is_empty() {
+        report.push_str("\nThese notes still link to it:");
+        for note in &deleted.linked_from {
+            report.push_str(&format!("\n- {} ({})", note.title, file_name(&note.path)));
+        }
+    }
+    report
+}
+
+/// Deletes the latest of `user`'s captures that's still around.
+async fn handle_undo(
+    bot: &Bot,
+    app: &App,
+    user: &CapturebotConfig,
+    msg: &Message,
+) -> ResponseResult<()> {
+    let Some(user_notes) = app.notes.read().await.get(&user.user_id).cloned() else {
+        return Ok(());
+    };
+    let mut notes = user_notes.lock().await;
+    let mut acks = app.acks.lock().await;
+    let report = loop {
+        let Some(capturebot_id) = acks.latest_capture(user.user_id).map(str::to_string) else {
+            break "There's nothing left to undo.".to_string();
+        };
+        acks.forget_capture(user.user_id, &capturebot_id);
+        match delete_note(&capturebot_id, &mut notes, user).await {
+            Ok(deleted) => break deletion_report(&deleted),
+            // deleted some other way since
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                log::error!("couldn't delete note {capturebot_id}: {e}");
+                acks.record_capture(user.user_id, capturebot_id);
+                break format!("Couldn't undo that: {e}");
+            }
+        }
+    };
+    save_acks(&acks, user).await;
+    drop(acks);
+    drop(notes);
+    bot.send_message(msg.chat.id, report)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+    Ok(())
+}
+
+/// Deletes the note of the message `msg` replies to, which is either the
+/// captured message or the bot's acknowledgement of it.
+async fn handle_delete(
+    bot: &Bot,
+    app: &App,
+    user: &CapturebotConfig,
+    msg: &Message,
+) -> ResponseResult<()> {
+    let reply = |text: String| {
+        bot.send_message(msg.chat.id, text)
+            .reply_parameters(ReplyParameters::new(msg.id))
+    };
+    let Some(replied) = msg
+        .reply_to_message()
+        .filter(|r| r.forum_topic_created().is_none())
+    else {
+        reply("Send /delete as a reply to a capture or to my acknowledgement of it.".to_string())
+            .await?;
+        return Ok(());
+    };
+    let ack = app.acks.lock().await.get(replied.chat.id.0, replied.id.0).cloned();
+    let capturebot_id = match ack {
+        Some(ack) if ack.user_id != user.user_id => {
+            reply("That note isn't yours to delete.".to_string()).await?;
+            return Ok(());
+        }
+        Some(ack) => ack.capturebot_id,
+        None => message_capturebot_id(replied),
+    };
+    let Some(user_notes) = app.notes.read().await.get(&user.user_id).cloned() else {
+        return Ok(());
+    };
+    let mut notes = user_notes.lock().await;
+    let report = match delete_note(&capturebot_id, &mut notes, user).await {
+        Ok(deleted) => {
+            let mut acks = app.acks.lock().await;
+            acks.forget_capture(user.user_id, &capturebot_id);
+            save_acks(&acks, user).await;
+            deletion_report(&deleted)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            "That isn't a note I know of.".to_string()
+        }
+        Err(e) => {
+            log::error!("couldn't delete note {capturebot_id}: {e}");
+            format!("Couldn't delete it: {e}")
+        }
+    };
+    drop(notes);
+    reply(report).await?;
+    Ok(())
+}
+
+/// Answers `/search` with the best matches among `user`'s notes, each with
+/// a button that sends the whole note. Only in private chats, so that notes
+/// don't end up in front of everyone else in a group.
+async fn handle_search(
+    bot: &Bot,
+    app: &App,
+    user: &CapturebotConfig,
+    msg: &Message,
+    terms: &str,
+) -> ResponseResult<()> {
+    let reply = |text: String| {
+        bot.send_message(msg.chat.id, text)
+            .reply_parameters(ReplyParameters::new(msg.id))
+    };
+    // results show notes, which aren't for whoever else is in the chat
+    if !msg.chat.is_private() {
+        reply(SEARCH_ELSEWHERE.to_string()).await?;
+        return Ok(());
+    }
+    let query: Query = match terms.parse() {
+        Ok(query) => query,
+        Err(e) => {
+            reply(format!("{e}\n\n{SEARCH_USAGE}")).await?;
+            return Ok(());
+        }
+    };
+    let Some(user_notes) = app.notes.read().await.get(&user.user_id).cloned() else {
+        return Ok(());
+    };
+    let notes = query.admitted(&*user_notes.lock().await);
+    let hits = search(&query, &notes, user, MAX_SEARCH_RESULTS).await;
+    if hits.is_empty() {
+        reply("Nothing matches that.".to_string()).await?;
+        return Ok(());
+    }
+    let mut text = String::new();
+    let mut buttons = Vec::new();
+    for (i, hit) in hits.iter().enumerate() {
+        let date = hit
+            .note
+            .created
+            .map_or("undated".to_string(), |c| c.format("%Y-%m-%d").to_string());
+        text.push_str(&format!("{}. {} ({date})\n{}\n\n", i + 1, hit.note.title, hit.snippet));
+        buttons.push(InlineKeyboardButton::callback(
+            (i + 1).to_string(),
+            format!("{SEARCH_OPEN}{}", hit.note.capturebot_id),
+        ));
+    }
+    reply(text.trim_end().to_string())
+        .reply_markup(InlineKeyboardMarkup::new([buttons]))
+        .await?;
+    Ok(())
+}
+
+fn truncate_message(text: &str) -> String {
+    if text.chars().count() <= MAX_MESSAGE_LENGTH {
+        return text.to_string();
+    }
+    let mut truncated: String = text.chars().take(MAX_MESSAGE_LENGTH - 1).collect();
+    truncated.push('…');
+    truncated
+}
+
+/// Sends the whole of a note picked from search results to whoever picked
+/// it, privately, as long as it's one of their own.
+async fn handle_search_open(
+    bot: Bot,
+    app: App,
+    q: CallbackQuery,
+    capturebot_id: &str,
+) -> ResponseResult<()> {
+    let user_notes = app.notes.read().await.get(&q.from.id.0).cloned();
+    let note = match user_notes {
+        Some(user_notes) => user_notes.lock().await.get(capturebot_id).cloned(),
+        None => None,
+    };
+    let Some(note) = note else {
+        bot.answer_callback_query(q.id.clone())
+            .text("That note isn't around anymore")
+            .await?;
+        return Ok(());
+    };
+    match read_note_source(&note).await {
+        Ok(source) => {
+            // to their private chat, wherever the results were
+            bot.send_message(q.from.id, truncate_message(&source)).await?;
+            bot.answer_callback_query(q.id.clone()).await?;
+        }
+        Err(e) => {
+            log::error!("couldn't read note {capturebot_id}: {e}");
+            bot.answer_callback_query(q.id.clone())
+                .text(format!("Couldn't read it: {e}"))
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Takes a reply to one of the bot's questions about a note as the answer
+/// to it.
+async fn answer_prompt(
+    bot: &Bot,
+    app: &App,
+    config: &CapturebotConfig,
+    msg: &Message,
+    text: &str,
+    asked: Ack,
+) -> ResponseResult<()> {
+    let Some((prompt, ack_id)) = asked.prompt else {
+        return Ok(());
+    };
+    if msg.from.as_ref().map(|u| u.id.0) != Some(asked.user_id) {
+        return Ok(());
+    }
+    let Some(user_notes) = app.notes.read().await.get(&asked.user_id).cloned() else {
+        return Ok(());
+    };
+    let reply = |text: String| {
+        bot.send_message(msg.chat.id, text)
+            .reply_parameters(ReplyParameters::new(msg.id))
+    };
+
+    let mut notes = user_notes.lock().await;
+    let edited = match prompt {
+        Prompt::Tag => {
+            let tags = parse_tags(text);
+            if tags.is_empty() {
+                reply("Those aren't tags org can use, try letters, digits and _".to_string()).await?;
+                return Ok(());
+            }
+            tag_note(&asked.capturebot_id, &tags, &mut notes)
+                .await
+                .map(|note| (note, None))
+        }
+        Prompt::Link => {
+            let target = match find_notes(text, &notes).as_slice() {
+                [target] => (target.capturebot_id.clone(), target.title.clone()),
+                [] => {
+                    reply("No note matches that, reply again with another title or ID".to_string())
+                        .await?;
+                    return Ok(());
+                }
+                several => {
+                    let titles: Vec<String> = several
+                        .iter()
+                        .take(MAX_LISTED_NOTES)
+                        .map(|n| format!("- {} ({})", n.title, n.id))
+                        .collect();
+                    reply(format!(
+                        "{} notes match, reply again with more of the title or the ID:\n{}",
+                        several.len(),
+                        titles.join("\n")
+                    ))
+                    .await?;
+                    return Ok(());
+                }
+            };
+            let (target_id, title) = target;
+            link_note(&asked.capturebot_id, &target_id, &mut notes)
+                .await
+                .map(|note| (note, Some(format!("Linked to \"{title}\""))))
+        }
+    };
+    drop(notes);
+    match edited {
+        Ok((note, done)) => {
+            let question = msg.reply_to_message().map(|q| q.id);
+            if let Some(question) = question {
+                let mut acks = app.acks.lock().await;
+                acks.remove(msg.chat.id.0, question.0);
+                save_acks(&acks, config).await;
+                drop(acks);
+                bot.delete_message(msg.chat.id, question).await.ok();
+            }
+            update_ack(bot, app, config, msg.chat.id, MessageId(ack_id), &note, done).await
+        }
+        Err(e) => {
+            log::error!("couldn't change note {}: {e}", asked.capturebot_id);
+            reply(format!("Couldn't change the note: {e}")).await?;
+            Ok(())
+        }
+    }
+}
+
+/// Keeps notes of channel posts in step with edits to the posts.
+async fn handle_edited_post(app: App, msg: Message) -> ResponseResult<()> {
+    let config = app.config.read().await.clone();
+    if !Message::is_valid_msg(msg.clone(), &config) {
+        return Ok(());
+    }
+    let Some((user, user_notes)) = capture_destination(&app, &config, &msg).await else {
+        return Ok(());
+    };
+    let mut notes_guard = user_notes.lock().await;
+    update_note(msg, &mut notes_guard, &user)
+        .await
+        .map_err(|e| RequestError::Io(e.into()))?;
+    Ok(())
+}
+
+/// Logs someone who isn't a user, at most once an hour each, and asks the
+/// owner what to do about them the first time they write.
+async fn handle_stranger(
+    bot: &Bot,
+    app: &App,
+    config: &CapturebotConfig,
+    from: &User,
+    text: &str,
+) -> ResponseResult<()> {
+    let mut access = app.access.lock().await;
+    let sighting = access.record(from, text, Utc::now());
+    if sighting == Sighting::Quiet {
+        return Ok(());
+    }
+    let Some(stranger) = access.get(from.id.0).cloned() else {
+        return Ok(());
+    };
+    access
+        .save(&config.access_log_path())
+        .await
+        .inspect_err(|e| log::error!("couldn't save access log: {e}"))
+        .ok();
+    drop(access);
+
+    let who = match &stranger.username {
+        Some(username) => format!("{} (@{username}, {})", stranger.name, stranger.user_id),
+        None => format!("{} ({})", stranger.name, stranger.user_id),
+    };
+    log::warn!(
+        "unauthorized message from {who}, {} so far, first one: {:?}",
+        stranger.count,
+        stranger.first_message
+    );
+    if sighting == Sighting::New {
+        let buttons = InlineKeyboardMarkup::new([[
+            InlineKeyboardButton::callback("Allow", format!("{ACCESS_ALLOW}{}", stranger.user_id)),
+            InlineKeyboardButton::callback("Block", format!("{ACCESS_BLOCK}{}", stranger.user_id)),
+        ]]);
+        bot.send_message(
+            ChatId(config.user_id as i64),
+            format!("{who} wants to save notes:\n\n{}", stranger.first_message),
+        )
+        .reply_markup(buttons)
+        .await
+        .inspect_err(|e| log::error!("couldn't tell the owner about {who}: {e}"))
+        .ok();
+    }
+    bot.send_message(ChatId(from.id.0 as i64), REFUSAL).await?;
+    Ok(())
+}
+
+/// The text an inline result puts in the chat: the note's title with the
+/// links it was captured from.
+fn shared_note_text(note: &CapturebotNote) -> String {
+    std::iter::once(note.title.as_str())
+        .chain(note.refs.iter().map(String::as_str))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Answers `@bot words` typed in any chat with the matching notes of
+/// whoever typed it, newest first when nothing's typed yet. Everyone else
+/// gets no results, and notes tagged private are never offered.
+async fn handle_inline_query(bot: Bot, app: App, q: InlineQuery) -> ResponseResult<()> {
+    let config = app.config.read().await.clone();
+    let blocked = app.access.lock().await.is_blocked(q.from.id.0);
+    let user = config.for_user(q.from.id.0).filter(|_| !blocked);
+    let query = Query::for_sharing(&q.query);
+    let user_notes = match user {
+        Some(user) => app.notes.read().await.get(&user.user_id).cloned(),
+        None => None,
+    };
+    let mut results = Vec::new();
+    if let Some(user) = user
+        && let Some(user_notes) = user_notes
+        && let Ok(query) = query
+    {
+        let notes = query.admitted(&*user_notes.lock().await);
+        for hit in search(&query, &notes, user, MAX_INLINE_RESULTS).await {
+            let content = InputMessageContentText::new(shared_note_text(hit.note));
+            let description = hit.note.refs.first().cloned().unwrap_or(hit.snippet);
+            results.push(InlineQueryResult::Article(
+                InlineQueryResultArticle::new(
+                    &hit.note.capturebot_id,
+                    &hit.note.title,
+                    InputMessageContent::Text(content),
+                )
+                .description(description),
+            ));
+        }
+    }
+    // results are someone's own notes, so telegram mustn't hand them to
+    // anyone else typing the same thing
+    bot.answer_inline_query(q.id, results)
+        .is_personal(true)
+        .cache_time(0)
+        .await?;
+    Ok(())
+}
+
+async fn handle_callback(bot: Bot, app: App, q: CallbackQuery) -> ResponseResult<()> {
+    let data = q.data.as_deref().unwrap_or_default();
+    if [NOTE_TAG, NOTE_TODO, NOTE_READ_LATER, NOTE_UNDO, NOTE_LINK].contains(&data) {
+        return handle_note_action(bot, app, q).await;
+    }
+    if let Some(capturebot_id) = data.strip_prefix(SEARCH_OPEN) {
+        let capturebot_id = capturebot_id.to_string();
+        return handle_search_open(bot, app, q, &capturebot_id).await;
+    }
+    let decision = if let Some(id) = data.strip_prefix(ACCESS_ALLOW) {
+        Some((id, AccessStatus::Allowed, "Allowed"))
+    } else {
+        data.strip_prefix(ACCESS_BLOCK)
+            .map(|id| (id, AccessStatus::Blocked, "Blocked"))
+    };
+    let Some((Ok(user_id), status, verdict)) = decision.map(|(id, s, v)| (id.parse::<u64>(), s, v))
+    else {
+        bot.answer_callback_query(q.id).await?;
+        return Ok(());
+    };
+    let config = app.config.read().await.clone();
+    if q.from.id.0 != config.user_id {
+        bot.answer_callback_query(q.id)
+            .text("Only the owner can decide that")
+            .await?;
+        return Ok(());
+    }
+
+    let mut access = app.access.lock().await;
+    if !access.decide(user_id, status) {
+        bot.answer_callback_query(q.id).text("I don't know that user").await?;
+        return Ok(());
+    }
+    access
+        .save(&config.access_log_path())
+        .await
+        .map_err(|e| RequestError::Io(e.into()))?;
+    drop(access);
+    if status == AccessStatus::Allowed {
+        tokio::fs::create_dir_all(config.guest_dir(user_id))
+            .await
+            .map_err(|e| RequestError::Io(e.into()))?;
+        app.reload.send(()).ok();
+        bot.send_message(ChatId(user_id as i64), WELCOME).await.ok();
+    }
+    log::info!("{verdict} user {user_id}");
+
+    if let Some(message) = q.regular_message() {
+        let text = message.text().unwrap_or_default();
+        bot.edit_message_text(message.chat.id, message.id, format!("{text}\n\n{verdict}."))
+            .await?;
+    }
+    bot.answer_callback_query(q.id).text(verdict).await?;
+    Ok(())
+}
 
 #[tokio::main]
 async fn main() {
-    pretty_env_logger::init();
+    logging::init();
     log::info!("Starting capturebot...");
 
-    let config = CapturebotConfig::from_env();
-    let notes = Arc::new(Mutex::new(HashMap::new()));
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let config_path = take_config_arg(&mut args);
+    let mut config = match CapturebotConfig::load(config_path.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+    if let Some(token) = &config.bot_token {
+        logging::redact(token.expose());
+    }
 
-    {
-        let mut notes_guard = notes.lock().await;
-        load_notes(&mut notes_guard, &config)
-            .await
-            .expect("notes should all load before we can proceed");
+    match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        [] => {}
+        ["reindex"] => {
+            for user in config.all_users() {
+                match reindex(user).await {
+                    Ok(report) => println!("user {}: {}", user.user_id, report.summary()),
+                    Err(e) => eprintln!("user {}: reindexing failed: {e}", user.user_id),
+                }
+            }
+            return;
+        }
+        ["search", terms @ ..] if !terms.is_empty() => {
+            let query: Query = match terms.join(" ").parse() {
+                Ok(query) => query,
+                Err(e) => {
+                    eprintln!("{e}");
+                    std::process::exit(2);
+                }
+            };
+            let mut notes = HashMap::new();
+            if let Err(e) = load_notes(&mut notes, &config).await {
+                eprintln!("couldn't load notes: {e}");
+                std::process::exit(1);
+            }
+            // one note per line, for scripts and editors to pick apart
+            for hit in search(&query, &notes, &config, MAX_CLI_SEARCH_RESULTS).await {
+                let date = hit.note.created.map_or(String::new(), |c| c.format("%Y-%m-%d").to_string());
+                println!(
+                    "{}\t{}\t{date}\t{}\t{}",
+                    hit.note.id,
+                    hit.note.path.display(),
+                    hit.note.title,
+                    hit.snippet
+                );
+            }
+            return;
+        }
+        ["config", "check"] => {
+            if config.bot_token.is_none() {
+                eprintln!("warning: no bot token configured");
+            }
+            print!("{}", config.resolved_toml());
+            return;
+        }
+        other => {
+            eprintln!("unknown command {:?}", other.join(" "));
+            eprintln!("usage: capturebot [--config <path>] [reindex | search <query> | config check]");
+            std::process::exit(2);
+        }
+    }
+    for user in config.all_users() {
+        log::info!(
+            "capturing messages from {} into {}",
+            user.user_id,
+            user.save_dir.display()
+        );
     }
 
-    let bot = Bot::from_env();
+    let Some(token) = &config.bot_token else {
+        eprintln!(
+            "no bot token: set bot_token_file or bot_token in the config, provide a bot-token \
+             credential, or set TELOXIDE_TOKEN"
+        );
+        std::process::exit(1);
+    };
+    let bot = Bot::new(token.expose());
+    if config.bot_username.is_none() {
+        let me = bot.get_me().await.expect("should be able to reach Telegram");
+        config.bot_username = Some(me.username().to_string());
+    }
 
-    teloxide::repl(bot, move |_bot: Bot, msg: Message| {
-        let notes_clone = notes.clone();
-	let config_clone = config.clone();
-        async move {
-            // bot.send_dice(msg.chat.id).await?;
-	    if is_valid_msg(msg.clone(), &config_clone) {
-		let mut notes_guard = notes_clone.lock().await;
-		add_note(msg, &mut notes_guard, &config_clone)
-                    .await
-                    .map_err(|e| RequestError::Io(e.into()))?;
-	    }
-            Ok(())
+    let (notes, reports) = load_user_notes(&config)
+        .await
+        .expect("notes should all load before we can proceed");
+    for (user_id, report) in reports {
+        if let Some(user) = config.for_user(user_id) {
+            report_load(&bot, user, &report).await;
         }
-    })
-    .await;
+    }
+    let notes = Arc::new(RwLock::new(notes));
+
+    let config = Arc::new(RwLock::new(config));
+    let (changes_tx, changes_rx) = mpsc::unbounded_channel();
+    let reload_tx = changes_tx.clone();
+    let _config_watcher = config_path.as_deref().and_then(|path| {
+        watch_config(path, changes_tx.clone())
+            .inspect_err(|e| log::error!("couldn't watch {}: {e}", path.display()))
+            .ok()
+    });
+    let mut hangups = signal(SignalKind::hangup()).expect("should be able to handle SIGHUP");
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            log::info!("got SIGHUP, reloading config");
+            changes_tx.send(()).ok();
+        }
+    });
+    tokio::spawn(reload_on_change(
+        bot.clone(),
+        config_path,
+        config.clone(),
+        notes.clone(),
+        changes_rx,
+    ));
+
+    let access_log_path = config.read().await.access_log_path();
+    let acks_path = config.read().await.acks_path();
+    let app = App {
+        config,
+        notes,
+        access: Arc::new(Mutex::new(AccessLog::load(&access_log_path))),
+        acks: Arc::new(Mutex::new(Acks::load(&acks_path))),
+        reload: reload_tx,
+    };
+    let handler = dptree::entry()
+        .branch(Update::filter_message().endpoint(handle_message))
+        .branch(Update::filter_channel_post().endpoint(handle_message))
+        .branch(Update::filter_edited_channel_post().endpoint(handle_edited_post))
+        .branch(Update::filter_callback_query().endpoint(handle_callback))
+        .branch(Update::filter_inline_query().endpoint(handle_inline_query));
+    Dispatcher::builder(bot, handler)
+        .dependencies(dptree::deps![app])
+        .enable_ctrlc_handler()
+        .build()
+        .dispatch()
+        .await;
 }
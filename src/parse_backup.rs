@@ -1,22 +1,18 @@
-#![feature(iter_intersperse)]
 use std::{
     collections::HashMap,
     fs::File,
     io::{Error, Read},
-    path::{Path, PathBuf},
+    path::PathBuf,
 };
 
 use capturebot::{
-    load_notes, CapturebotConfig, CapturebotNote, ContextualFrom, ValidMessage,
-    CAPTUREBOT_ID_PROPERTY, CAPTUREBOT_PARENT_ID_PROPERTY,
+    load_notes, save_note, take_config_arg, Capture, CapturebotConfig, CapturebotNote,
+    ContextualFrom, ValidMessage,
 };
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::from_str;
-use slugify::slugify;
 use teloxide::types::{Chat, User};
-use tokio::fs;
-use uuidgen::gen_uuid;
 
 pub mod serde_user_id {
     use serde::{Deserialize, Deserializer};
@@ -43,6 +39,7 @@ pub mod serde_date_from_unix_timestamp {
         chrono::DateTime::from_timestamp(timestamp, 0).ok_or_else(|| E::custom("invalid timestamp"))
     }
 
+    #[allow(dead_code)]
     pub(crate) fn serialize<S>(this: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
@@ -169,14 +166,14 @@ pub mod serde_date_from_unix_timestamp {
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(untagged)]
-enum BackupText {
+pub enum BackupText {
     String(String),
     Parts(Vec<TextPart>),
 }
 
-impl ToString for BackupText {
-    fn to_string(&self) -> String {
-        match self {
+impl std::fmt::Display for BackupText {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
             Self::String(s) => s.to_string(),
             Self::Parts(parts) => parts
                 .iter()
@@ -185,7 +182,8 @@ impl ToString for BackupText {
                     TextPart::Entity(BackupEntity { kind: _, text }) => text.as_str(),
                 })
                 .collect::<String>(),
-        }
+        };
+        write!(f, "{s}")
     }
 }
 
@@ -204,11 +202,14 @@ pub struct BackupMessage {
     pub reply_to_message_id: Option<i32>,
     #[serde(rename = "text_entities")]
     pub entities: Vec<BackupEntity>,
+    /// Filled in from the enclosing export, which records the chat once.
+    #[serde(skip)]
+    pub chat_id: u64,
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(untagged)]
-enum TextPart {
+pub enum TextPart {
     String(String),
     Entity(BackupEntity),
 }
@@ -258,6 +259,7 @@ pub enum BackupEntityKind {
     },
 }
 
+#[allow(dead_code)]
 #[derive(Deserialize)]
 struct TelegramBackup {
     name: String,
@@ -280,18 +282,7 @@ impl ContextualFrom<BackupMessage, &HashMap<String, CapturebotNote>, &Capturebot
         notes: &HashMap<String, CapturebotNote>,
         config: &CapturebotConfig,
     ) -> Result<Self, Self::Error> {
-        let text = msg.text.to_string();
-        let title = text.lines().next().map_or(
-            format!("capturebot note made at {}", Utc::now()),
-            str::to_string,
-        );
-        let target_path = format!(
-            "{s}/{d}-{t}.org",
-            s = config.save_dir.display(),
-            d = msg.date.format("%Y%m%d%H%M%S"),
-            t = slugify!(&title, max_length = 30)
-        );
-        let links = msg
+        let refs = msg
             .entities
             .iter()
             .filter_map(|e| match e.clone().kind {
@@ -299,47 +290,19 @@ impl ContextualFrom<BackupMessage, &HashMap<String, CapturebotNote>, &Capturebot
                 BackupEntityKind::TextLink { href } => Some(format!("[[{}][{}]]", e.text, href)),
                 _ => None,
             })
-            .intersperse(", ".to_string())
-            .collect::<String>();
-        let timestamp = msg.date.format("[%Y-%m-%d %a %H:%M]");
-        let org_id = gen_uuid(true);
-        let cap_id = msg.id.to_string();
-        let reply = msg.reply_to_message_id;
-        let cap_parent_id_property_string = reply.map_or(String::new(), |rt| {
-            format!("\n:{CAPTUREBOT_PARENT_ID_PROPERTY}: {}", rt.to_string())
-        });
-        let org_parent_link_string = reply.map_or(String::new(), |rt| {
-            notes.get(&rt.to_string()).map_or(String::new(), |pn| {
-                format!("* Related: [[id:{}][{}]]\n", pn.id.to_string(), pn.title)
-            })
-        });
-        let target_path = format!(
-            "{s}/{d}-{t}.org",
-            s = config.save_dir.display(),
-            d = msg.date.format("%Y%m%d%H%M%S"),
-            t = slugify!(&title, max_length = 30)
-        );
-        let note_body = format!(
-            ":PROPERTIES:
-:ID: {org_id}
-:CREATED: {timestamp}
-:{CAPTUREBOT_ID_PROPERTY}: {cap_id}{cap_parent_id_property_string}
-:ROAM_REFS: {links}
-:END:
-#+title: {title}
-{text}
-{org_parent_link_string}
-"
-        );
-
-        Ok(CapturebotNote {
-            id: org_id,
-            path: PathBuf::from(target_path),
+            .collect::<Vec<String>>();
+        let capture = Capture {
+            date: msg.date,
+            text: msg.text.to_string(),
+            refs,
+            tags: Vec::new(),
             capturebot_id: msg.id.to_string(),
-            _capturebot_parent: msg.reply_to_message_id.map(|rt| rt.to_string()),
-            title,
-            body: note_body,
-        })
+            capturebot_parent: msg.reply_to_message_id.map(|rt| rt.to_string()),
+            chat: msg.chat_id.to_string(),
+            properties: Vec::new(),
+            org_id: None,
+        };
+        CapturebotNote::contextual_from(capture, notes, config)
     }
 }
 
@@ -353,36 +316,41 @@ pub async fn add_backup_note(
         Ok(())
     } else {
         println!("noting {:?} : {:?}", msg.id, msg.text);
-        let new_note = CapturebotNote::contextual_from(msg, notes, config)?;
-        fs::write(Path::new(&new_note.path), new_note.body.clone())
-            .await
-            .and_then(|_| {
-                notes.insert(new_note.capturebot_id.clone(), new_note);
-                Ok(())
-            })?;
+        let mut new_note = CapturebotNote::contextual_from(msg, notes, config)?;
+        save_note(&mut new_note, notes, config).await?;
+        notes.insert(new_note.capturebot_id.clone(), new_note);
         Ok(())
     }
 }
 
 #[tokio::main]
 async fn main() {
-    let config = CapturebotConfig::from_env();
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let config_path = take_config_arg(&mut args);
+    let config = CapturebotConfig::load(config_path.as_deref()).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(1)
+    });
     let backup_location = config.backup_json.clone().unwrap_or_else(|| {
-        PathBuf::from(std::env::args().nth(1).expect("no backup location given"))
-    }); // put this in config.rs later
+        PathBuf::from(args.first().expect("no backup location given"))
+    });
     let mut file = File::open(backup_location).unwrap();
     let mut data = String::new();
     file.read_to_string(&mut data).unwrap();
     let json: TelegramBackup = from_str(&data).expect("backup file should be parseable as json");
     let mut notes = HashMap::new();
-    load_notes(&mut notes, &config)
+    let report = load_notes(&mut notes, &config)
         .await
         .expect("load_notes failed");
-    for msg in json.messages {
+    if !report.is_clean() {
+        eprintln!("{}", report.summary());
+    }
+    for mut msg in json.messages {
+        msg.chat_id = json.id;
         if BackupMessage::is_valid_msg(msg.clone(), &config) {
             add_backup_note(msg.clone(), &mut notes, &config)
                 .await
-                .map_err(|e| println!("parsing message {} failed: {}", msg.id.to_string(), e))
+                .map_err(|e| println!("parsing message {} failed: {}", msg.id, e))
                 .expect("");
         } else {
             println!("invalid message {:?}: {:?}", msg.id, msg)
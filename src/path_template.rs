@@ -0,0 +1,168 @@
+use std::fmt::{self, Display};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, FixedOffset};
+
+pub static DEFAULT_PATH_TEMPLATE: &str = "%<%Y%m%d%H%M%S>-${slug}.org";
+
+static VARIABLES: [&str; 5] = ["slug", "tag", "tags", "chat", "id"];
+
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    Date(String),
+    Variable(String),
+}
+
+/// Where a new note goes, relative to `save_dir`, written in org-roam's
+/// capture template syntax: `%<...>` takes a strftime format for the note's
+/// date, and `${slug}`, `${tag}`, `${tags}`, `${chat}` and `${id}` are
+/// replaced with the title slug, the first tag, all tags joined by `_`, the
+/// chat ID and the message ID. Notes without tags get "untagged" for both,
+/// and any other empty value "none".
+#[derive(Clone, Debug, PartialEq)]
+pub struct PathTemplate {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct PathTemplateError(String);
+
+impl Display for PathTemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid path template: {}", self.0)
+    }
+}
+
+impl std::error::Error for PathTemplateError {}
+
+/// What a note's path can be built from. `date` is in the configured
+/// timezone.
+pub struct PathContext<'a> {
+    pub date: DateTime<FixedOffset>,
+    pub slug: &'a str,
+    pub tags: &'a [String],
+    pub chat: &'a str,
+    pub id: &'a str,
+}
+
+impl FromStr for PathTemplate {
+    type Err = PathTemplateError;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut rest = source;
+        while !rest.is_empty() {
+            let (segment, tail) = if let Some(tail) = rest.strip_prefix("%<") {
+                let end = tail
+                    .find('>')
+                    .ok_or(PathTemplateError(format!("unterminated %< in {source:?}")))?;
+                let format = &tail[..end];
+                if StrftimeItems::new(format).any(|i| i == Item::Error) {
+                    return Err(PathTemplateError(format!("bad date format {format:?}")));
+                }
+                (Segment::Date(format.to_string()), &tail[end + 1..])
+            } else if let Some(tail) = rest.strip_prefix("${") {
+                let end = tail
+                    .find('}')
+                    .ok_or(PathTemplateError(format!("unterminated ${{ in {source:?}")))?;
+                let name = &tail[..end];
+                if !VARIABLES.contains(&name) {
+                    return Err(PathTemplateError(format!(
+                        "unknown variable ${{{name}}}, expected one of {}",
+                        VARIABLES.join(", ")
+                    )));
+                }
+                (Segment::Variable(name.to_string()), &tail[end + 1..])
+            } else {
+                let c = rest.chars().next().unwrap_or_default();
+                literal.push(c);
+                rest = &rest[c.len_utf8()..];
+                continue;
+            };
+            if !literal.is_empty() {
+                segments.push(Segment::Literal(std::mem::take(&mut literal)));
+            }
+            segments.push(segment);
+            rest = tail;
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+
+        if !source.ends_with(".org") {
+            return Err(PathTemplateError(format!("{source:?} should end in .org")));
+        }
+        if !Path::new(source)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            return Err(PathTemplateError(format!(
+                "{source:?} should be a relative path that stays inside the save directory"
+            )));
+        }
+        Ok(Self {
+            source: source.to_string(),
+            segments,
+        })
+    }
+}
+
+impl Default for PathTemplate {
+    fn default() -> Self {
+        DEFAULT_PATH_TEMPLATE
+            .parse()
+            .expect("the default path template should be valid")
+    }
+}
+
+impl Display for PathTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+// keeps substituted variables from adding or escaping directories; dates
+// aren't user input, and may make directories of their own
+fn path_safe(value: &str) -> String {
+    value.replace(['/', '\\'], "-").replace("..", "-")
+}
+
+impl PathTemplate {
+    /// Renders the template into a path relative to the save directory.
+    pub fn render(&self, context: &PathContext) -> PathBuf {
+        let rendered: String = self
+            .segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Literal(l) => l.clone(),
+                Segment::Date(format) => context.date.format(format).to_string(),
+                Segment::Variable(name) => {
+                    let value = match name.as_str() {
+                        "slug" => context.slug.to_string(),
+                        "tag" => context.tags.first().cloned().unwrap_or_default(),
+                        "tags" => context.tags.join("_"),
+                        "chat" => context.chat.to_string(),
+                        "id" => context.id.to_string(),
+                        _ => unreachable!("variables are checked when parsing"),
+                    };
+                    match value.as_str() {
+                        "" if name.starts_with("tag") => "untagged".to_string(),
+                        "" => "none".to_string(),
+                        _ => path_safe(&value),
+                    }
+                }
+            })
+            .collect();
+        // only plain names, so that nothing rendered can make the path
+        // absolute or leave the save directory
+        Path::new(&rendered)
+            .components()
+            .filter(|c| matches!(c, Component::Normal(_)))
+            .collect()
+    }
+}
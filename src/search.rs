@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use chrono::NaiveDate;
+
+use crate::{fulltext, read_note_source, CapturebotConfig, CapturebotNote};
+
+// a term in the title counts for this many in the text
+const TITLE_WEIGHT: usize = 3;
+const SNIPPET_LENGTH: usize = 120;
+/// Notes with this tag, their own or inherited, are never offered for
+/// sharing, since other people in the chat get to see those results.
+pub static PRIVATE_TAG: &str = "private";
+
+/// What `/search` was asked for: words and `"quoted phrases"` that must all
+/// appear in a note's title or text, ignoring case, narrowed down by `tag:`,
+/// `-tag:`, `url:`, `before:` and `after:` filters. Dates are `YYYY-MM-DD`;
+/// `before:` is strictly before and `after:` on or after.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Query {
+    pub terms: Vec<String>,
+    pub tags: Vec<String>,
+    /// Tags a note mustn't have.
+    pub without_tags: Vec<String>,
+    pub urls: Vec<String>,
+    pub before: Option<NaiveDate>,
+    pub after: Option<NaiveDate>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct QueryError(String);
+
+impl Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid search: {}", self.0)
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+// the words of `source`, with quoted phrases kept together and unquoted
+fn words(source: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut rest = source.trim_start();
+    while !rest.is_empty() {
+        let (word, after) = match rest.strip_prefix('"') {
+            Some(quoted) => quoted.split_once('"').unwrap_or((quoted, "")),
+            None => rest.split_once(char::is_whitespace).unwrap_or((rest, "")),
+        };
+        let word = word.split_whitespace().collect::<Vec<_>>().join(" ");
+        if !word.is_empty() {
+            words.push(word);
+        }
+        rest = after.trim_start();
+    }
+    words
+}
+
+impl FromStr for Query {
+    type Err = QueryError;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let date = |value: &str| {
+            NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .map_err(|_| QueryError(format!("{value:?} isn't a YYYY-MM-DD date")))
+        };
+        let mut query = Query::default();
+        for word in words(source) {
+            if word.contains(' ') {
+                query.terms.push(word.to_lowercase());
+                continue;
+            }
+            match word.split_once(':') {
+                Some(("tag", tag)) if !tag.is_empty() => {
+                    query.tags.push(tag.trim_start_matches('#').to_lowercase())
+                }
+                Some(("-tag", tag)) if !tag.is_empty() => {
+                    query.without_tags.push(tag.trim_start_matches('#').to_lowercase())
+                }
+                Some(("url", url)) if !url.is_empty() => query.urls.push(url.to_lowercase()),
+                Some(("before", value)) => query.before = Some(date(value)?),
+                Some(("after", value)) => query.after = Some(date(value)?),
+                _ => query.terms.push(word.to_lowercase()),
+            }
+        }
+        if query == Query::default() {
+            return Err(QueryError("there's nothing to search for".to_string()));
+        }
+        Ok(query)
+    }
+}
+
+impl Query {
+    /// The query for notes offered to share in a chat: like a parsed query,
+    /// but with nothing typed finding every note, and private notes left out.
+    pub fn for_sharing(source: &str) -> Result<Query, QueryError> {
+        let mut query = if source.trim().is_empty() {
+            Query::default()
+        } else {
+            source.parse()?
+        };
+        query.without_tags.push(PRIVATE_TAG.to_string());
+        Ok(query)
+    }
+
+    /// Copies of the notes that pass the filters, to search once the lock on
+    /// `notes` is let go, since searching can mean reading every one of them.
+    pub fn admitted(
+        &self,
+        notes: &HashMap<String, CapturebotNote>,
+    ) -> HashMap<String, CapturebotNote> {
+        notes
+            .iter()
+            .filter(|(_, note)| self.admits(note))
+            .map(|(id, note)| (id.clone(), note.clone()))
+            .collect()
+    }
+
+    /// Whether `note` passes the filters, leaving its text aside.
+    pub(crate) fn admits(&self, note: &CapturebotNote) -> bool {
+        let date = note.created.map(|c| c.date());
+        self.tags
+            .iter()
+            .all(|tag| note.all_tags().any(|t| t.eq_ignore_ascii_case(tag)))
+            && !self
+                .without_tags
+                .iter()
+                .any(|tag| note.all_tags().any(|t| t.eq_ignore_ascii_case(tag)))
+            && self
+                .urls
+                .iter()
+                .all(|url| note.refs.iter().any(|r| r.to_lowercase().contains(url)))
+            && self.before.is_none_or(|before| date.is_some_and(|d| d < before))
+            && self.after.is_none_or(|after| date.is_some_and(|d| d >= after))
+    }
+}
+
+/// A note that matched a search, with a bit of its text around the first
+/// match.
+#[derive(Debug)]
+pub struct SearchHit<'a> {
+    pub note: &'a CapturebotNote,
+    pub snippet: String,
+    pub(crate) score: f32,
+}
+
+/// The words of a note's org source, without its property drawers and
+/// keyword lines, on one line.
+pub(crate) fn note_text(source: &str) -> String {
+    let mut in_drawer = false;
+    let mut words = Vec::new();
+    for line in source.lines() {
+        let trimmed = line.trim();
+        if trimmed == ":PROPERTIES:" {
+            in_drawer = true;
+            continue;
+        }
+        if in_drawer {
+            in_drawer = trimmed != ":END:";
+            continue;
+        }
+        if trimmed.starts_with("#+") {
+            continue;
+        }
+        words.extend(trimmed.split_whitespace());
+    }
+    words.join(" ")
+}
+
+pub(crate) fn snippet(text: &str, terms: &[String]) -> String {
+    // lowercasing can turn one character into several, so keep track of
+    // which character of `text` each byte of `lower` came from
+    let mut lower = String::new();
+    let mut origins = Vec::new();
+    for (i, c) in text.chars().enumerate() {
+        for l in c.to_lowercase() {
+            lower.push(l);
+            origins.resize(lower.len(), i);
+        }
+    }
+    let first_match = terms.iter().filter_map(|t| lower.find(t.as_str())).min();
+    let start = first_match.map_or(0, |i| origins[i].saturating_sub(SNIPPET_LENGTH / 4));
+    let mut snippet: String = text.chars().skip(start).take(SNIPPET_LENGTH).collect();
+    if start > 0 {
+        snippet.insert(0, '…');
+    }
+    if text.chars().count() > start + SNIPPET_LENGTH {
+        snippet.push('…');
+    }
+    snippet
+}
+
+/// The best `limit` matches for `query` in `notes`, most relevant first.
+/// They're looked up in the full-text index when `config` has one, and
+/// otherwise, or if the index can't be used, found by going through every
+/// note.
+pub async fn search<'a>(
+    query: &Query,
+    notes: &'a HashMap<String, CapturebotNote>,
+    config: &CapturebotConfig,
+    limit: usize,
+) -> Vec<SearchHit<'a>> {
+    if let Some(index_dir) = &config.fulltext_index {
+        match fulltext::search(index_dir, query, notes, limit).await {
+            Ok(hits) => return hits,
+            Err(e) => log::warn!("full-text search failed, scanning notes instead: {e}"),
+        }
+    }
+    scan(query, notes, limit).await
+}
+
+/// The best `limit` matches for `query` in `notes`, most relevant first and
+/// newest first among equals. Notes loaded through the index cache are read
+/// back from disk to be searched.
+async fn scan<'a>(
+    query: &Query,
+    notes: &'a HashMap<String, CapturebotNote>,
+    limit: usize,
+) -> Vec<SearchHit<'a>> {
+    let mut hits = Vec::new();
+    for note in notes.values().filter(|n| query.admits(n)) {
+        let source = if note.body.is_empty() {
+            read_note_source(note).await.unwrap_or_default()
+        } else {
+            note.body.clone()
+        };
+        let text = note_text(&source);
+        let title = note.title.to_lowercase();
+        let lower_text = text.to_lowercase();
+        let mut score = 0;
+        let mut matches_all = true;
+        for term in &query.terms {
+            let found = title.matches(term.as_str()).count() * TITLE_WEIGHT
+                + lower_text.matches(term.as_str()).count();
+            if found == 0 {
+                matches_all = false;
+                break;
+            }
+            score += found;
+        }
+        if matches_all {
+            hits.push(SearchHit {
+                note,
+                snippet: snippet(&text, &query.terms),
+                score: score as f32,
+            });
+        }
+    }
+    hits.sort_by(|a, b| {
+        b.score
+            .total_cmp(&a.score)
+            .then(b.note.created.cmp(&a.note.created))
+    });
+    hits.truncate(limit);
+    hits
+}
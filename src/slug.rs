@@ -0,0 +1,79 @@
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use deunicode::deunicode;
+use slugify::slugify;
+
+const MAX_SLUG_LENGTH: usize = 30;
+// slugs shorter than this say too little about the note to stand on their own
+const MIN_SLUG_LENGTH: usize = 3;
+
+/// How note titles are turned into file name slugs.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SlugStyle {
+    /// Transliterated to lowercase ASCII, so "Привет мир" becomes "privet-mir".
+    #[default]
+    Ascii,
+    /// Lowercased but otherwise left in its own script, so "Привет мир"
+    /// becomes "привет-мир".
+    Unicode,
+}
+
+impl FromStr for SlugStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "ascii" => Ok(Self::Ascii),
+            "unicode" => Ok(Self::Unicode),
+            other => Err(format!("unknown slug style {other:?}, expected ascii or unicode")),
+        }
+    }
+}
+
+impl Display for SlugStyle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Ascii => "ascii",
+            Self::Unicode => "unicode",
+        })
+    }
+}
+
+// FNV-1a, which unlike std's hasher is guaranteed to give the same answer
+// across builds
+fn title_hash(title: &str) -> String {
+    let hash = title
+        .bytes()
+        .fold(0x811c9dc5u32, |hash, b| (hash ^ b as u32).wrapping_mul(0x01000193));
+    format!("{hash:08x}")
+}
+
+fn unicode_slug(title: &str) -> String {
+    title
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .intersperse("-")
+        .collect::<String>()
+        .chars()
+        .take(MAX_SLUG_LENGTH)
+        .collect::<String>()
+        .trim_end_matches('-')
+        .to_string()
+}
+
+/// The file name slug for a note titled `title`. Titles that don't leave
+/// enough of a slug behind, like emoji-only ones, get a short hash of the
+/// title instead so that file names stay distinct.
+pub fn slug(title: &str, style: SlugStyle) -> String {
+    let slug = match style {
+        SlugStyle::Ascii => slugify!(&deunicode(title), max_length = MAX_SLUG_LENGTH),
+        SlugStyle::Unicode => unicode_slug(title),
+    };
+    match slug.chars().count() {
+        0 => title_hash(title),
+        n if n < MIN_SLUG_LENGTH => format!("{slug}-{}", title_hash(title)),
+        _ => slug,
+    }
+}
@@ -1,13 +1,24 @@
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests {
     use std::collections::HashMap;
-    use std::env;
-    use std::path::Path;
-    use chrono::Utc;
-    use teloxide::types::{Chat, ChatId, ChatKind, ChatPrivate, MediaKind, MediaText, Message, MessageCommon, MessageId, MessageKind, User, UserId};
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use chrono::{DateTime, TimeDelta, Utc};
+    use teloxide::types::{Chat, ChatId, ChatKind, ChatPrivate, ChatPublic, MediaKind, MediaText, Message, MessageCommon, ForumTopicCreated, MessageEntity, MessageEntityKind, MessageForumTopicCreated, MessageId, MessageKind, PublicChatChannel, PublicChatKind, Rgb, ThreadId, User, UserId};
     use tokio::fs;
-    use crate::{load_notes, is_valid_msg, add_note, note_from_message};
-    use crate::config::CapturebotConfig;
+    use tokio::sync::{Mutex, RwLock};
+    use crate::{load_notes, add_note, update_note, tag_note, todo_note, link_note, delete_note, find_notes, load_user_notes, message_topic, read_note_source, reload_config, split_refs, CapturebotNote, ContextualFrom, ValidMessage};
+    use crate::access::{AccessLog, AccessStatus, Sighting};
+    use crate::ack::{Ack, Acks, MAX_ACKS};
+    use crate::config::{CaptureTarget, CapturebotConfig, ChatConfig, NoteFormat, TopicConfig};
+    use crate::path_template::{PathContext, PathTemplate};
+    use crate::fulltext;
+    use crate::search::{search, snippet, Query};
+    use crate::slug::{slug, SlugStyle};
+    use crate::timezone::Timezone;
+    use crate::watch::watch_notes;
 
 
     // Helper function to create a test message
@@ -120,14 +131,14 @@ mod tests {
         let config = CapturebotConfig::for_testing("test_is_valid_msg");
         
         let valid_msg = create_test_message(1, "Test message", None);
-        assert!(is_valid_msg(valid_msg, &config), "Message should be valid");
+        assert!(Message::is_valid_msg(valid_msg, &config), "Message should be valid");
         
         // Create a message with different user ID
         let mut invalid_user_msg = create_test_message(2, "Test message", None);
         if let Some(user) = invalid_user_msg.from.as_mut() {
             user.id = UserId(99999); // Different user ID
         }
-        assert!(!is_valid_msg(invalid_user_msg, &config), "Message with wrong user ID should be invalid");
+        assert!(!Message::is_valid_msg(invalid_user_msg, &config), "Message with wrong user ID should be invalid");
     }
 
     #[tokio::test]
@@ -140,7 +151,7 @@ mod tests {
         let notes = HashMap::new();
         
         // Generate note from message
-        let note = note_from_message(msg.clone(), &notes, &config);
+        let note = CapturebotNote::contextual_from(msg.clone(), &notes, &config).unwrap();
         
         // Verify note properties
         assert_eq!(note.title, "Test Title");
@@ -150,6 +161,604 @@ mod tests {
         assert!(note.path.to_str().unwrap().contains(".org"));
     }
 
+    #[test]
+    fn test_path_template() {
+        let date = DateTime::parse_from_rfc3339("2024-05-06T07:08:09Z").unwrap();
+        let tags = vec!["reading".to_string(), "rust".to_string()];
+        let context = PathContext { date, slug: "a-title", tags: &tags, chat: "42", id: "7" };
+
+        // The default matches org-roam's own file naming
+        assert_eq!(
+            PathTemplate::default().render(&context),
+            PathBuf::from("20240506070809-a-title.org")
+        );
+
+        let dated: PathTemplate = "%<%Y>/%<%m>/${id}-${slug}.org".parse().unwrap();
+        assert_eq!(dated.render(&context), PathBuf::from("2024/05/7-a-title.org"));
+        let monthly: PathTemplate = "%<%Y/%m>/${slug}.org".parse().unwrap();
+        assert_eq!(monthly.render(&context), PathBuf::from("2024/05/a-title.org"));
+
+        let tagged: PathTemplate = "${tag}/${chat}-${tags}.org".parse().unwrap();
+        assert_eq!(tagged.render(&context), PathBuf::from("reading/42-reading_rust.org"));
+
+        // Empty values get a placeholder rather than an empty directory name
+        let untagged = PathContext { tags: &[], chat: "", ..context };
+        let by_tags: PathTemplate = "${tags}/${chat}${slug}.org".parse().unwrap();
+        assert_eq!(by_tags.render(&untagged), PathBuf::from("untagged/nonea-title.org"));
+        let mut config = CapturebotConfig::for_testing("test_path_template");
+        config.path_template = by_tags;
+        assert!(config.note_path(&untagged).starts_with(&config.save_dir));
+
+        // Bad templates are caught up front
+        assert!("${nope}.org".parse::<PathTemplate>().is_err(), "Unknown variables should be rejected");
+        assert!("%<%Y.org".parse::<PathTemplate>().is_err(), "Unterminated dates should be rejected");
+        assert!("../${slug}.org".parse::<PathTemplate>().is_err(), "Templates should stay in save_dir");
+        assert!("${slug}.txt".parse::<PathTemplate>().is_err(), "Templates should make org files");
+    }
+
+    #[test]
+    fn test_slug() {
+        assert_eq!(slug("Hello, World!", SlugStyle::Ascii), "hello-world");
+        assert_eq!(slug("Привет мир", SlugStyle::Ascii), "privet-mir");
+        assert_eq!(slug("Привет мир", SlugStyle::Unicode), "привет-мир");
+        assert!(!slug("नमस्ते दुनिया", SlugStyle::Ascii).is_empty());
+        assert!(!slug("你好世界", SlugStyle::Ascii).is_empty());
+
+        // Titles with nothing to slug fall back to a stable hash
+        let hashed = slug("🙂", SlugStyle::Unicode);
+        assert_eq!(hashed.len(), 8);
+        assert_eq!(hashed, slug("🙂", SlugStyle::Unicode));
+        assert_ne!(hashed, slug("🙃", SlugStyle::Unicode));
+    }
+
+    #[test]
+    fn test_split_refs() {
+        assert_eq!(
+            split_refs("https://a.example, https://b.example https://c.example"),
+            vec!["https://a.example", "https://b.example", "https://c.example"]
+        );
+        // link descriptions keep their spaces
+        assert_eq!(
+            split_refs("[[https://x.example][some title]] https://y.example"),
+            vec!["[[https://x.example][some title]]", "https://y.example"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_note_timezone() {
+        let mut config = CapturebotConfig::for_testing("test_note_timezone");
+        config.timezone = "Asia/Kolkata".parse().unwrap();
+        assert!("Not/AZone".parse::<Timezone>().is_err());
+
+        let mut msg = create_test_message(5001, "Late Night\nbody", None);
+        msg.date = DateTime::parse_from_rfc3339("2024-05-06T23:30:00Z").unwrap().to_utc();
+        let note = CapturebotNote::contextual_from(msg, &HashMap::new(), &config).unwrap();
+
+        // Both the file name and CREATED use local time, not UTC
+        assert!(note.path.ends_with("20240507050000-late-night.org"));
+        assert!(note.body.contains(":CREATED: [2024-05-07 Tue 05:00]"));
+    }
+
+    #[tokio::test]
+    async fn test_config_file() -> Result<(), std::io::Error> {
+        let dir = PathBuf::from("/tmp/test_out/test_config_file/");
+        let _ = fs::remove_dir_all(&dir).await;
+        fs::create_dir_all(dir.join("notes")).await?;
+        let path = dir.join("capturebot.toml");
+
+        fs::write(&path, format!(
+            "user_id = 777\nsave_dir = \"{}\"\nnote_format = \"denote\"\ntimezone = \"Europe/Berlin\"\ninbox = \"inbox.org\"\n",
+            dir.join("notes").display()
+        )).await?;
+        let config = CapturebotConfig::load(Some(&path)).unwrap();
+        assert_eq!(config.user_id, 777);
+        assert_eq!(config.read_dir, config.save_dir);
+        assert_eq!(config.note_format, NoteFormat::Denote);
+        assert_eq!(config.capture_target, CaptureTarget::Inbox(dir.join("notes/inbox.org")));
+        // the resolved config reads back as the same config
+        fs::write(&path, config.resolved_toml()).await?;
+        let reloaded = CapturebotConfig::load(Some(&path)).unwrap();
+        assert_eq!(reloaded.capture_target, config.capture_target);
+        assert_eq!(reloaded.timezone, config.timezone);
+
+        // every problem is reported at once
+        fs::write(&path, format!(
+            "user_id = 777\nsave_dir = \"{}\"\nslug_style = \"klingon\"\n",
+            dir.join("missing").display()
+        )).await?;
+        let error = CapturebotConfig::load(Some(&path)).err().unwrap().to_string();
+        assert!(error.contains("missing doesn't exist"), "{error}");
+        assert!(error.contains("klingon"), "{error}");
+
+        fs::write(&path, "user_id = 777\nsave_dri = \"typo\"\n").await?;
+        let error = CapturebotConfig::load(Some(&path)).err().unwrap().to_string();
+        assert!(error.contains("save_dri"), "{error}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bot_token() -> Result<(), std::io::Error> {
+        let dir = PathBuf::from("/tmp/test_out/test_bot_token/");
+        let _ = fs::remove_dir_all(&dir).await;
+        fs::create_dir_all(&dir).await?;
+        let token = "123456:secret-token";
+        fs::write(dir.join("token"), format!("{token}\n")).await?;
+        let path = dir.join("capturebot.toml");
+        fs::write(&path, format!(
+            "user_id = 1\nsave_dir = \"{0}\"\nbot_token = \"ignored\"\nbot_token_file = \"{0}token\"\n",
+            dir.display()
+        )).await?;
+
+        // the token file wins over the inline token
+        let config = CapturebotConfig::load(Some(&path)).unwrap();
+        let bot_token = config.bot_token.clone().unwrap();
+        assert_eq!(bot_token.expose(), token);
+        assert!(!format!("{bot_token:?} {bot_token}").contains(token));
+        assert!(!config.resolved_toml().contains(token));
+
+        fs::remove_file(dir.join("token")).await?;
+        let error = CapturebotConfig::load(Some(&path)).err().unwrap().to_string();
+        assert!(error.contains("bot token"), "{error}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_multiple_users() -> Result<(), std::io::Error> {
+        let dir = PathBuf::from("/tmp/test_out/test_multiple_users/");
+        let _ = fs::remove_dir_all(&dir).await;
+        fs::create_dir_all(dir.join("mine")).await?;
+        fs::create_dir_all(dir.join("partner")).await?;
+        let path = dir.join("capturebot.toml");
+        let users = |partner_dir: &str| format!(
+            "user_id = 12345\nsave_dir = \"{0}mine\"\ntimezone = \"Asia/Kolkata\"\n\n\
+             [[users]]\nuser_id = 999\nsave_dir = \"{0}{partner_dir}\"\npath_template = \"${{slug}}.org\"\n",
+            dir.display()
+        );
+
+        fs::write(&path, users("partner")).await?;
+        let config = CapturebotConfig::load(Some(&path)).unwrap();
+        let partner = config.for_user(999).unwrap();
+        assert_eq!(partner.read_dir, dir.join("partner"));
+        // note settings carry over from the top level, directories don't
+        assert_eq!(partner.timezone, config.timezone);
+        assert!(config.for_user(1).is_none());
+        // the state directory is shared, so only the top level sets it
+        assert_eq!(partner.trash_dir, config.state_dir.join("trash").join("999"));
+        fs::write(&path, format!("{}state_dir = \"{}state\"\n", users("partner"), dir.display())).await?;
+        let error = CapturebotConfig::load(Some(&path)).err().unwrap().to_string();
+        assert!(error.contains("state_dir"), "{error}");
+
+        let mut msg = create_test_message(6001, "Partner Note\nbody", None);
+        msg.from.as_mut().unwrap().id = UserId(999);
+        assert!(Message::is_valid_msg(msg.clone(), &config));
+        let (user_notes, _) = load_user_notes(&config).await?;
+        add_note(msg, &mut *user_notes[&999].lock().await, partner).await?;
+        assert!(dir.join("partner/partner-note.org").exists());
+        assert!(user_notes[&12345].lock().await.is_empty());
+
+        // nobody gets to write into someone else's notes
+        fs::write(&path, users("mine")).await?;
+        let error = CapturebotConfig::load(Some(&path)).err().unwrap().to_string();
+        assert!(error.contains("share save_dir"), "{error}");
+
+        // nor into someone else's search index
+        let shared_index = format!("fulltext_index = \"{}index\"\n", dir.display());
+        fs::write(&path, format!("{shared_index}{}{shared_index}", users("partner"))).await?;
+        let error = CapturebotConfig::load(Some(&path)).err().unwrap().to_string();
+        assert!(error.contains("share fulltext_index"), "{error}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_access_log() -> Result<(), std::io::Error> {
+        let dir = PathBuf::from("/tmp/test_out/test_access_log/");
+        let _ = fs::remove_dir_all(&dir).await;
+        fs::create_dir_all(dir.join("notes")).await?;
+        let path = dir.join("capturebot.toml");
+        fs::write(&path, format!(
+            "user_id = 12345\nsave_dir = \"{0}notes\"\nstate_dir = \"{0}state\"\n",
+            dir.display()
+        )).await?;
+        let config = CapturebotConfig::load(Some(&path)).unwrap();
+
+        let stranger = create_test_message(1, "", None).from.map(|mut u| {
+            u.id = UserId(4242);
+            u
+        }).unwrap();
+        let now = Utc::now();
+        let mut access = AccessLog::default();
+        assert_eq!(access.record(&stranger, "hello there", now), Sighting::New);
+        // repeat messages are counted but only logged once in a while
+        assert_eq!(access.record(&stranger, "hello?", now), Sighting::Quiet);
+        assert_eq!(access.record(&stranger, "anyone?", now + TimeDelta::hours(2)), Sighting::Logged);
+        assert_eq!(access.get(4242).unwrap().count, 3);
+        assert_eq!(access.get(4242).unwrap().first_message, "hello there");
+
+        // letting someone in survives a restart and gives them notes of their own
+        assert!(access.decide(4242, AccessStatus::Allowed));
+        assert!(!access.decide(1, AccessStatus::Blocked));
+        access.save(&config.access_log_path()).await?;
+        let config = CapturebotConfig::load(Some(&path)).unwrap();
+        let guest = config.for_user(4242).unwrap();
+        assert_eq!(guest.save_dir, dir.join("state/guests/4242"));
+        assert_eq!(guest.timezone, config.timezone);
+
+        let mut access = AccessLog::load(&config.access_log_path());
+        access.decide(4242, AccessStatus::Blocked);
+        assert!(access.is_blocked(4242));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_acks() -> Result<(), std::io::Error> {
+        let config = CapturebotConfig::for_testing("test_acks");
+        let _ = fs::remove_dir_all(&config.save_dir).await;
+        let _ = fs::remove_dir_all(&config.state_dir).await;
+        let mut notes = HashMap::new();
+
+        let note = add_note(create_test_message(1, "Acknowledged", None), &mut notes, &config)
+            .await?
+            .expect("a new message should make a note");
+        assert_eq!(note.title, "Acknowledged");
+        // capturing the same message again makes nothing new to acknowledge
+        assert!(add_note(create_test_message(1, "Acknowledged", None), &mut notes, &config).await?.is_none());
+
+        let mut acks = Acks::default();
+        let ack = Ack::new(config.user_id, note.capturebot_id.clone());
+        acks.insert(0, 2, ack.clone());
+        acks.save(&config.acks_path()).await?;
+        let acks = Acks::load(&config.acks_path());
+        assert_eq!(acks.get(0, 2), Some(&ack));
+        assert_eq!(acks.get(-100, 2), None);
+
+        // only the latest acknowledgements are kept
+        let mut acks = Acks::default();
+        for message_id in 0..=MAX_ACKS as i32 {
+            acks.insert(0, message_id, ack.clone());
+        }
+        assert_eq!(acks.get(0, 0), None);
+        assert_eq!(acks.get(0, MAX_ACKS as i32), Some(&ack));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_note_actions() -> Result<(), std::io::Error> {
+        let config = CapturebotConfig::for_testing("test_note_actions");
+        let _ = fs::remove_dir_all(&config.save_dir).await;
+        let mut notes = HashMap::new();
+        add_note(create_test_message(1, "Tagged File\nbody", None), &mut notes, &config).await?;
+        add_note(create_test_message(2, "Link Target", None), &mut notes, &config).await?;
+        let path = notes["1"].path.clone();
+
+        // file notes get their tags in the front matter, merged with any
+        // already there
+        tag_note("1", &["reading".to_string()], &mut notes).await?;
+        let note = tag_note("1", &["reading".to_string(), "later".to_string()], &mut notes).await?;
+        assert_eq!(note.tags, vec!["reading".to_string(), "later".to_string()]);
+        let source = fs::read_to_string(&path).await?;
+        assert!(source.contains("#+title: Tagged File\n#+filetags: :reading:later:\n"), "{source}");
+
+        todo_note("1", &mut notes).await?;
+        todo_note("1", &mut notes).await?;
+        assert_eq!(fs::read_to_string(&path).await?.matches("* TODO Tagged File").count(), 1);
+
+        assert_eq!(find_notes("link tar", &notes).len(), 1);
+        let target_id = notes["2"].id.clone();
+        link_note("1", "2", &mut notes).await?;
+        assert!(link_note("1", "1", &mut notes).await.is_err());
+        let source = fs::read_to_string(&path).await?;
+        assert!(source.contains(&format!("* Related: [[id:{target_id}][Link Target]]")), "{source}");
+
+        let mut loaded = HashMap::new();
+        load_notes(&mut loaded, &config).await?;
+        assert_eq!(loaded["1"].tags, vec!["reading".to_string(), "later".to_string()]);
+
+        delete_note("1", &mut notes, &config).await?;
+        assert!(!path.exists());
+        assert!(!notes.contains_key("1"));
+
+        // heading notes are changed on their heading line, leaving the rest
+        // of the inbox alone
+        let mut inbox_config = config.clone();
+        let inbox = config.save_dir.join("inbox.org");
+        inbox_config.capture_target = CaptureTarget::Inbox(inbox.clone());
+        fs::write(&inbox, "#+title: Inbox\n").await?;
+        add_note(create_test_message(3, "Inbox Entry\ntext", None), &mut notes, &inbox_config).await?;
+        add_note(create_test_message(4, "Second Entry", None), &mut notes, &inbox_config).await?;
+        tag_note("3", &["work".to_string()], &mut notes).await?;
+        todo_note("3", &mut notes).await?;
+        link_note("3", "2", &mut notes).await?;
+        let source = fs::read_to_string(&inbox).await?;
+        assert!(source.contains("\n* TODO Inbox Entry :work:\n"), "{source}");
+        assert!(source.contains(&format!("text\n** Related: [[id:{target_id}][Link Target]]\n* Second Entry")), "{source}");
+
+        delete_note("3", &mut notes, &inbox_config).await?;
+        let source = fs::read_to_string(&inbox).await?;
+        assert!(!source.contains("Inbox Entry"), "{source}");
+        assert!(source.starts_with("#+title: Inbox\n* Second Entry"), "{source}");
+        assert!(notes.contains_key("4"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delete_note() -> Result<(), std::io::Error> {
+        let config = CapturebotConfig::for_testing("test_delete_note");
+        let _ = fs::remove_dir_all(&config.save_dir).await;
+        let _ = fs::remove_dir_all(&config.trash_dir).await;
+        let mut notes = HashMap::new();
+        let mut acks = Acks::default();
+        for msg in [
+            create_test_message(1, "Wrong Thing", None),
+            create_test_message(2, "Reply To It", Some(1)),
+            create_test_message(3, "Latest", None),
+        ] {
+            let note = add_note(msg, &mut notes, &config).await?.unwrap();
+            acks.record_capture(config.user_id, note.capturebot_id);
+        }
+        let path = notes["1"].path.clone();
+        let source = fs::read_to_string(&path).await?;
+
+        // deleted notes go to the trash, and whatever linked to them is
+        // reported
+        let deleted = delete_note("1", &mut notes, &config).await?;
+        assert!(!path.exists());
+        assert!(deleted.trash_path.starts_with(&config.trash_dir));
+        assert_eq!(fs::read_to_string(&deleted.trash_path).await?, source);
+        let linking: Vec<&str> = deleted.linked_from.iter().map(|n| n.title.as_str()).collect();
+        assert_eq!(linking, vec!["Reply To It"]);
+        assert!(!notes.contains_key("1"));
+        assert_eq!(delete_note("1", &mut notes, &config).await.unwrap_err().kind(), std::io::ErrorKind::NotFound);
+
+        // /undo goes back through the latest captures
+        assert_eq!(acks.latest_capture(config.user_id), Some("3"));
+        acks.forget_capture(config.user_id, "3");
+        assert_eq!(acks.latest_capture(config.user_id), Some("2"));
+
+        // a trash inside the notes would be loaded right back
+        let dir = PathBuf::from("/tmp/test_out/test_delete_note/");
+        fs::create_dir_all(dir.join("notes")).await?;
+        let path = dir.join("capturebot.toml");
+        fs::write(&path, format!(
+            "user_id = 1\nsave_dir = \"{0}notes\"\ntrash_dir = \"{0}notes/trash\"\n",
+            dir.display()
+        )).await?;
+        let error = CapturebotConfig::load(Some(&path)).err().unwrap().to_string();
+        assert!(error.contains("trash_dir"), "{error}");
+        // however the two are written
+        let up = std::env::current_dir()?.components().count() - 1;
+        let relative = PathBuf::from_iter(std::iter::repeat_n("..", up)).join("tmp/test_out/test_delete_note/notes/trash");
+        fs::write(&path, format!(
+            "user_id = 1\nsave_dir = \"{}./notes/\"\ntrash_dir = \"{}\"\n",
+            dir.display(),
+            relative.display()
+        )).await?;
+        let error = CapturebotConfig::load(Some(&path)).err().unwrap().to_string();
+        assert!(error.contains("trash_dir"), "{error}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_search() -> Result<(), std::io::Error> {
+        let mut config = CapturebotConfig::for_testing("test_search");
+        let _ = fs::remove_dir_all(&config.save_dir).await;
+        config.index_cache = None;
+        let mut notes = HashMap::new();
+        let mut older = create_test_message(1, "Sourdough starter\nfeed it flour and water daily", None);
+        older.date = "2024-03-01T10:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let mut newer = create_test_message(2, "Bread notes\nthe sourdough came out flat", None);
+        newer.date = "2024-06-01T10:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        add_note(older, &mut notes, &config).await?;
+        add_note(newer, &mut notes, &config).await?;
+        add_note(create_test_message(3, "Unrelated\nnothing to see", None), &mut notes, &config).await?;
+        tag_note("2", &["baking".to_string()], &mut notes).await?;
+
+        // a match in the title counts for more
+        let hits = search(&"sourdough".parse().unwrap(), &notes, &config, 5).await;
+        let titles: Vec<&str> = hits.iter().map(|h| h.note.title.as_str()).collect();
+        assert_eq!(titles, vec!["Sourdough starter", "Bread notes"]);
+        assert!(hits[1].snippet.contains("the sourdough came out flat"), "{}", hits[1].snippet);
+        assert!(!hits[1].snippet.contains("CAPTUREBOT"), "{}", hits[1].snippet);
+        // characters that lowercase to several don't throw the snippet off
+        let text = format!("{} sourdough rises", "İ".repeat(200));
+        assert_eq!(snippet(&text, &["sourdough".to_string()]), format!("…{} sourdough rises", "İ".repeat(29)));
+
+        let query: Query = "sourdough tag:baking".parse().unwrap();
+        let hits = search(&query, &notes, &config, 5).await;
+        assert_eq!(hits.len(), 1);
+        // what's searched without holding the notes gives the same answers
+        let admitted = query.admitted(&notes);
+        assert!(admitted.len() < notes.len());
+        assert_eq!(search(&query, &admitted, &config, 5).await[0].note.title, hits[0].note.title);
+        let hits = search(&"sourdough -tag:baking".parse().unwrap(), &notes, &config, 5).await;
+        assert_eq!(hits[0].note.title, "Sourdough starter");
+        assert_eq!(hits.len(), 1);
+        let hits = search(&"sourdough before:2024-04-01".parse().unwrap(), &notes, &config, 5).await;
+        assert_eq!(hits[0].note.title, "Sourdough starter");
+        assert_eq!(hits.len(), 1);
+        assert_eq!(search(&"sourdough after:2024-05-01".parse().unwrap(), &notes, &config, 5).await[0].note.title, "Bread notes");
+        assert!(search(&"sourdough rye".parse().unwrap(), &notes, &config, 5).await.is_empty());
+        assert!("before:soon".parse::<Query>().is_err());
+        assert!("".parse::<Query>().is_err());
+
+        // dates survive a reload, for the filters
+        let mut loaded = HashMap::new();
+        load_notes(&mut loaded, &config).await?;
+        assert_eq!(loaded["1"].created, notes["1"].created);
+        assert_eq!(search(&"flour after:2024-01-01".parse().unwrap(), &loaded, &config, 5).await.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fulltext_search() -> Result<(), std::io::Error> {
+        let mut config = CapturebotConfig::for_testing("test_fulltext_search");
+        let _ = fs::remove_dir_all(&config.save_dir).await;
+        let _ = fs::remove_dir_all(&config.state_dir).await;
+        let index_dir = config.state_dir.join("fulltext");
+        config.fulltext_index = Some(index_dir.clone());
+        let mut notes = HashMap::new();
+        let mut older = create_test_message(1, "Kitchen log\nbaked a loaf from the sourdough starter", None);
+        older.date = "2024-03-01T10:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let mut newer = create_test_message(2, "Kitchen diary\nbaked a loaf from the sourdough starter", None);
+        newer.date = "2024-06-01T10:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        add_note(older, &mut notes, &config).await?;
+        add_note(newer, &mut notes, &config).await?;
+        add_note(create_test_message(3, "Garden\nthe starter plants need sourdough, oddly", None), &mut notes, &config).await?;
+
+        // stems match, and equally good matches come newest first
+        let hits = search(&"baking".parse().unwrap(), &notes, &config, 5).await;
+        let titles: Vec<&str> = hits.iter().map(|h| h.note.title.as_str()).collect();
+        assert_eq!(titles, vec!["Kitchen diary", "Kitchen log"]);
+        let hits = search(&"\"sourdough starter\"".parse().unwrap(), &notes, &config, 5).await;
+        assert_eq!(hits.len(), 2);
+        assert_eq!(search(&"sourdough starter".parse().unwrap(), &notes, &config, 5).await.len(), 3);
+
+        // filters are answered by the index too
+        tag_note("3", &["garden".to_string()], &mut notes).await?;
+        fulltext::update(&[&notes["3"]], &[], &config).await;
+        assert_eq!(search(&"sourdough tag:garden".parse().unwrap(), &notes, &config, 5).await.len(), 1);
+        assert_eq!(search(&"sourdough -tag:garden".parse().unwrap(), &notes, &config, 5).await.len(), 2);
+        let hits = search(&"before:2024-04-01".parse().unwrap(), &notes, &config, 5).await;
+        assert_eq!(hits.iter().map(|h| h.note.title.as_str()).collect::<Vec<_>>(), vec!["Kitchen log"]);
+        assert_eq!(search(&"after:2024-03-01 before:2024-06-01".parse().unwrap(), &notes, &config, 5).await.len(), 1);
+        let mut linked = create_test_message(4, "Rye recipe\nhttps://Bread.example.com/rye", None);
+        if let MessageKind::Common(common) = &mut linked.kind
+            && let MediaKind::Text(media) = &mut common.media_kind
+        {
+            media.entities.push(MessageEntity::new(MessageEntityKind::Url, 11, 29));
+        }
+        add_note(linked, &mut notes, &config).await?;
+        let hits = search(&"url:bread.example".parse().unwrap(), &notes, &config, 5).await;
+        assert_eq!(hits.iter().map(|h| h.note.title.as_str()).collect::<Vec<_>>(), vec!["Rye recipe"]);
+        assert!(search(&"url:cake.example".parse().unwrap(), &notes, &config, 5).await.is_empty());
+
+        // a linked page that arrives late is added to the note as it is by
+        // then, and doesn't bring back a note that's gone
+        let mut edited = notes["4"].clone();
+        edited.title = "Rye bread".to_string();
+        fulltext::update(&[&edited], &[], &config).await;
+        notes.insert("4".to_string(), edited);
+        fulltext::index_article(&index_dir, "4", "a pumpernickel method".to_string()).await.unwrap();
+        let hits = search(&"pumpernickel".parse().unwrap(), &notes, &config, 5).await;
+        assert_eq!(hits.iter().map(|h| h.note.title.as_str()).collect::<Vec<_>>(), vec!["Rye bread"]);
+        assert_eq!(search(&"url:bread.example".parse().unwrap(), &notes, &config, 5).await.len(), 1);
+        let before_delete = notes.clone();
+        delete_note("4", &mut notes, &config).await?;
+        fulltext::index_article(&index_dir, "4", "a pumpernickel method".to_string()).await.unwrap();
+        assert!(search(&"pumpernickel".parse().unwrap(), &before_delete, &config, 5).await.is_empty());
+
+        // the index follows deletions, and picks up files changed while the
+        // bot wasn't looking when the notes are loaded
+        delete_note("2", &mut notes, &config).await?;
+        assert_eq!(search(&"baking".parse().unwrap(), &notes, &config, 5).await.len(), 1);
+        let path = notes["3"].path.clone();
+        let source = fs::read_to_string(&path).await?;
+        fs::write(&path, source.replace("oddly", "and rhubarb")).await?;
+        let mut loaded = HashMap::new();
+        load_notes(&mut loaded, &config).await?;
+        assert_eq!(search(&"rhubarb".parse().unwrap(), &loaded, &config, 5).await[0].note.title, "Garden");
+
+        // searching from scratch gives the same answers as the index
+        config.fulltext_index = None;
+        assert_eq!(search(&"rhubarb".parse().unwrap(), &loaded, &config, 5).await.len(), 1);
+        assert!(index_dir.join("meta.json").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_private_notes() -> Result<(), std::io::Error> {
+        let mut config = CapturebotConfig::for_testing("test_private_notes");
+        let _ = fs::remove_dir_all(&config.save_dir).await;
+        let _ = fs::remove_dir_all(&config.state_dir).await;
+        let inbox = config.save_dir.join("inbox.org");
+        fs::create_dir_all(&config.save_dir).await?;
+        fs::write(&inbox, "#+title: Journal\n#+filetags: :private:\n").await?;
+        config.capture_target = CaptureTarget::Inbox(inbox);
+        let mut notes = HashMap::new();
+        add_note(create_test_message(1, "Diary entry\nfeeling sourdough-ish", None), &mut notes, &config).await?;
+
+        // replies nest under their parent in a daily, inheriting its tags
+        let mut dailies_config = config.clone();
+        dailies_config.capture_target = CaptureTarget::Dailies(config.save_dir.join("daily"));
+        add_note(create_test_message(2, "Secret recipe\nsourdough with rye", None), &mut notes, &dailies_config).await?;
+        tag_note("2", &["Private".to_string()], &mut notes).await?;
+        add_note(create_test_message(3, "Recipe tweak\nmore sourdough starter", Some(2)), &mut notes, &dailies_config).await?;
+        add_note(create_test_message(4, "Bread club\nsourdough swap on friday", None), &mut notes, &dailies_config).await?;
+        assert_eq!(notes["1"].inherited_tags, vec!["private".to_string()]);
+        assert_eq!(notes["3"].inherited_tags, vec!["Private".to_string()]);
+        assert!(notes["4"].inherited_tags.is_empty());
+
+        // neither those nor the notes they're under are offered for sharing,
+        // whether they're searched for, listed, or loaded back
+        let shared = |notes: &HashMap<String, CapturebotNote>, config: &CapturebotConfig, source: &str| {
+            let query = Query::for_sharing(source).unwrap();
+            let notes = notes.clone();
+            let config = config.clone();
+            async move {
+                search(&query, &notes, &config, 10)
+                    .await
+                    .into_iter()
+                    .map(|h| h.note.capturebot_id.clone())
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(shared(&notes, &config, "sourdough").await, vec!["4"]);
+        assert_eq!(shared(&notes, &config, " ").await, vec!["4"]);
+        let mut loaded = HashMap::new();
+        load_notes(&mut loaded, &config).await?;
+        assert_eq!(shared(&loaded, &config, "sourdough").await, vec!["4"]);
+        assert_eq!(search(&"sourdough".parse().unwrap(), &loaded, &config, 10).await.len(), 4);
+
+        // and the same goes for the full-text index
+        config.fulltext_index = Some(config.state_dir.join("fulltext"));
+        let mut indexed = HashMap::new();
+        load_notes(&mut indexed, &config).await?;
+        assert_eq!(shared(&indexed, &config, "sourdough").await, vec!["4"]);
+        assert_eq!(shared(&indexed, &config, "").await, vec!["4"]);
+        assert_eq!(search(&"sourdough tag:private".parse().unwrap(), &indexed, &config, 10).await.len(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reload_config() -> Result<(), std::io::Error> {
+        let dir = PathBuf::from("/tmp/test_out/test_reload_config/");
+        let _ = fs::remove_dir_all(&dir).await;
+        fs::create_dir_all(dir.join("old")).await?;
+        fs::create_dir_all(dir.join("new")).await?;
+        fs::write(
+            dir.join("new/moved.org"),
+            ":PROPERTIES:\n:ID: moved-id\n:CAPTUREBOT_MESSAGE_ID: 42\n:END:\n#+title: Moved\n",
+        ).await?;
+        let path = dir.join("capturebot.toml");
+        let write_config = |save_dir: &str, timezone: &str| {
+            fs::write(&path, format!(
+                "user_id = 12345\nsave_dir = \"{}\"\ntimezone = \"{timezone}\"\n",
+                dir.join(save_dir).display()
+            ))
+        };
+
+        write_config("old", "UTC").await?;
+        let config = RwLock::new(CapturebotConfig::load(Some(&path)).unwrap());
+        let notes = RwLock::new(load_user_notes(&*config.read().await).await?.0);
+
+        // an invalid config leaves the old one in place
+        write_config("new", "Not/AZone").await?;
+        assert!(reload_config(Some(&path), &config, &notes).await.is_err());
+        assert_eq!(config.read().await.save_dir, dir.join("old"));
+
+        // a directory change reloads the notes from there
+        write_config("new", "Europe/Berlin").await?;
+        let reports = reload_config(Some(&path), &config, &notes).await.unwrap();
+        assert_eq!(reports.iter().map(|(_, r)| r.loaded).collect::<Vec<_>>(), vec![1]);
+        assert_eq!(config.read().await.save_dir, dir.join("new"));
+        assert!(notes.read().await[&12345].lock().await.contains_key("42"));
+
+        // other changes keep the notes as they are
+        write_config("new", "UTC").await?;
+        assert!(reload_config(Some(&path), &config, &notes).await.unwrap().is_empty());
+        assert_eq!(config.read().await.timezone, Timezone::default());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_load_notes() -> Result<(), std::io::Error> {
         // Set up test environment
@@ -180,6 +789,216 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_tolerant_load_notes() -> Result<(), std::io::Error> {
+        let mut test_config = CapturebotConfig::for_testing("test_tolerant_load_notes");
+        fs::create_dir_all(test_config.save_dir.as_path()).await?;
+
+        // One good note and one file that isn't valid UTF-8
+        let good_path = test_config.save_dir.join("20230101000000-good-note.org");
+        let bad_path = test_config.save_dir.join("20230101000001-bad-note.org");
+        let good_content = format!(
+            ":PROPERTIES:\n:ID: good-uuid\n:{}: 23456\n:END:\n#+title: Good Note\n",
+            crate::CAPTUREBOT_ID_PROPERTY
+        );
+        fs::write(&good_path, good_content).await?;
+        fs::write(&bad_path, [0xff, 0xfe, 0xfd]).await?;
+
+        // Strict loading gives up on the bad file
+        let mut notes = HashMap::new();
+        assert!(load_notes(&mut notes, &test_config).await.is_err(), "Strict load should fail");
+
+        // Tolerant loading reports it and keeps going
+        test_config.tolerant_load = true;
+        let mut notes = HashMap::new();
+        let report = load_notes(&mut notes, &test_config).await?;
+        assert!(notes.contains_key("23456"), "Good note should still be loaded");
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(report.failures[0].path, bad_path);
+        assert!(report.summary().contains("bad-note"));
+
+        // Clean up
+        fs::remove_file(&good_path).await?;
+        fs::remove_file(&bad_path).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_index_cache() -> Result<(), std::io::Error> {
+        let mut test_config = CapturebotConfig::for_testing("test_index_cache");
+        let cache_path = test_config.save_dir.join("index-cache.json");
+        test_config.index_cache = Some(cache_path.clone());
+        fs::create_dir_all(test_config.save_dir.as_path()).await?;
+
+        let note_path = test_config.save_dir.join("20230101000000-cached-note.org");
+        let note_content = |title: &str| {
+            format!(
+                ":PROPERTIES:\n:ID: cached-uuid\n:{}: 34567\n:ROAM_REFS: https://example.com\n:END:\n#+title: {}\n#+filetags: :reading:\n",
+                crate::CAPTUREBOT_ID_PROPERTY,
+                title
+            )
+        };
+        fs::write(&note_path, note_content("Cached Note")).await?;
+
+        // First load parses the file and writes the cache
+        let mut notes = HashMap::new();
+        let report = load_notes(&mut notes, &test_config).await?;
+        assert_eq!(report.from_cache, 0);
+        assert!(cache_path.exists(), "Cache file should be written");
+
+        // Second load is served from the cache, without the body
+        let mut notes = HashMap::new();
+        let report = load_notes(&mut notes, &test_config).await?;
+        assert_eq!(report.from_cache, 1);
+        let note = notes.get("34567").unwrap();
+        assert_eq!(note.title, "Cached Note");
+        assert_eq!(note.refs, vec!["https://example.com".to_string()]);
+        assert_eq!(note.tags, vec!["reading".to_string()]);
+        assert!(note.body.is_empty());
+
+        // Changing the file invalidates its entry
+        fs::write(&note_path, note_content("Renamed Cached Note")).await?;
+        let mut notes = HashMap::new();
+        let report = load_notes(&mut notes, &test_config).await?;
+        assert_eq!(report.from_cache, 0);
+        assert_eq!(notes.get("34567").unwrap().title, "Renamed Cached Note");
+
+        // Clean up
+        fs::remove_file(&note_path).await?;
+        fs::remove_file(&cache_path).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_watch_notes() -> Result<(), std::io::Error> {
+        let test_config = CapturebotConfig::for_testing("test_watch_notes");
+        fs::create_dir_all(test_config.save_dir.as_path()).await?;
+        fs::create_dir_all(test_config.read_dir.as_path()).await?;
+
+        let note_path = test_config.save_dir.join("20230101000000-watched-note.org");
+        let note_content = |title: &str| {
+            format!(
+                ":PROPERTIES:\n:ID: watched-uuid\n:{}: 45678\n:END:\n#+title: {}\n",
+                crate::CAPTUREBOT_ID_PROPERTY,
+                title
+            )
+        };
+        fs::write(&note_path, note_content("Watched Note")).await?;
+
+        let notes = Arc::new(Mutex::new(HashMap::new()));
+        load_notes(&mut *notes.lock().await, &test_config).await?;
+        let _watcher = watch_notes(notes.clone(), &test_config).expect("watcher should start");
+
+        // Polls the notes map until `check` passes or we give up
+        async fn wait_for(
+            notes: &Arc<Mutex<HashMap<String, CapturebotNote>>>,
+            check: impl Fn(&HashMap<String, CapturebotNote>) -> bool,
+        ) -> bool {
+            for _ in 0..50 {
+                if check(&*notes.lock().await) {
+                    return true;
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            false
+        }
+
+        // Edits show up in the map
+        fs::write(&note_path, note_content("Edited Watched Note")).await?;
+        assert!(
+            wait_for(&notes, |n| n.get("45678").is_some_and(|note| note.title == "Edited Watched Note")).await,
+            "Edited title should be picked up"
+        );
+
+        // Deleting the file drops the note
+        fs::remove_file(&note_path).await?;
+        assert!(
+            wait_for(&notes, |n| !n.contains_key("45678")).await,
+            "Deleted note should be forgotten"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_watch_relative_dirs() -> Result<(), std::io::Error> {
+        // the same place under /tmp as the other tests, but relative to the
+        // current directory, the way the default ./out/ is
+        let up = std::env::current_dir()?.components().count() - 1;
+        let dir = PathBuf::from_iter(std::iter::repeat_n("..", up)).join("tmp/test_out/test_watch_relative_dirs/");
+        let _ = fs::remove_dir_all(&dir).await;
+        let mut test_config = CapturebotConfig::for_testing("test_watch_relative_dirs");
+        test_config.save_dir = dir.clone();
+        test_config.read_dir = dir.clone();
+        fs::create_dir_all(&dir).await?;
+
+        let note_path = dir.join("notes.org");
+        let heading = |id: &str| {
+            format!("* Heading {id}\n:PROPERTIES:\n:ID: relative-{id}\n:{}: {id}\n:END:\n", crate::CAPTUREBOT_ID_PROPERTY)
+        };
+        fs::write(&note_path, format!("#+title: Notes\n{}{}", heading("1"), heading("2"))).await?;
+        let notes = Arc::new(Mutex::new(HashMap::new()));
+        load_notes(&mut *notes.lock().await, &test_config).await?;
+        assert!(notes.lock().await.contains_key("2"));
+        let _watcher = watch_notes(notes.clone(), &test_config).expect("watcher should start");
+
+        async fn wait_for(
+            notes: &Arc<Mutex<HashMap<String, CapturebotNote>>>,
+            check: impl Fn(&HashMap<String, CapturebotNote>) -> bool,
+        ) -> bool {
+            for _ in 0..50 {
+                if check(&*notes.lock().await) {
+                    return true;
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            false
+        }
+
+        // a heading cut from the file goes, and so does the rest with the file
+        fs::write(&note_path, format!("#+title: Notes\n{}", heading("1"))).await?;
+        assert!(wait_for(&notes, |n| !n.contains_key("2") && n.contains_key("1")).await, "Removed heading should be forgotten");
+        assert_eq!(notes.lock().await["1"].path, note_path);
+        fs::remove_file(&note_path).await?;
+        assert!(wait_for(&notes, |n| n.is_empty()).await, "Deleted file's notes should be forgotten");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_load_nested_headings() -> Result<(), std::io::Error> {
+        let test_config = CapturebotConfig::for_testing("test_load_nested_headings");
+        fs::create_dir_all(test_config.save_dir.as_path()).await?;
+
+        // A capture refiled two levels deep into an outline file
+        let outline_path = test_config.save_dir.join("reading.org");
+        let outline_content = format!(
+            "#+title: Reading\n* Books\n** Refiled Capture\n:PROPERTIES:\n:ID: nested-uuid\n:{}: 56789\n:END:\nNested body\n* Articles\n",
+            crate::CAPTUREBOT_ID_PROPERTY
+        );
+        fs::write(&outline_path, outline_content).await?;
+
+        let mut notes = HashMap::new();
+        load_notes(&mut notes, &test_config).await?;
+
+        let note = notes.get("56789").expect("Nested heading should be loaded");
+        assert_eq!(note.path, outline_path);
+        assert_eq!(note.outline_path, vec!["Books".to_string(), "Refiled Capture".to_string()]);
+
+        // The heading can be found again in its file
+        let source = read_note_source(note).await?;
+        assert!(source.starts_with("** Refiled Capture"));
+        assert!(source.contains("Nested body"));
+        assert!(!source.contains("Articles"));
+
+        // Clean up
+        fs::remove_file(&outline_path).await?;
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_add_note() -> Result<(), std::io::Error> {
         // Set up test environment
@@ -206,6 +1025,374 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_add_note_collision() -> Result<(), std::io::Error> {
+        let test_config = CapturebotConfig::for_testing("test_add_note_collision");
+        fs::create_dir_all(test_config.save_dir.as_path()).await?;
+
+        // Two messages with the same first line, sent in the same second
+        let first = create_test_message(321, "Same Title\nfirst body", None);
+        let mut second = create_test_message(322, "Same Title\nsecond body", None);
+        second.date = first.date;
+        let mut notes = HashMap::new();
+        add_note(first.clone(), &mut notes, &test_config).await?;
+        add_note(second.clone(), &mut notes, &test_config).await?;
+
+        let first_note = notes.get("321").unwrap();
+        let second_note = notes.get("322").unwrap();
+        assert_ne!(first_note.path, second_note.path, "Notes should not share a file");
+        assert!(fs::read_to_string(&first_note.path).await?.contains("first body"));
+        assert!(fs::read_to_string(&second_note.path).await?.contains("second body"));
+
+        // Clean up
+        fs::remove_file(&first_note.path).await?;
+        fs::remove_file(&second_note.path).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_denote_round_trip() -> Result<(), std::io::Error> {
+        let mut test_config = CapturebotConfig::for_testing("test_denote_round_trip");
+        test_config.note_format = NoteFormat::Denote;
+        fs::create_dir_all(test_config.save_dir.as_path()).await?;
+
+        let parent_msg = create_test_message(2001, "Denote Parent\nparent body", None);
+        let reply_msg = create_test_message(2002, "Denote Reply\nreply body", Some(2001));
+        let mut notes = HashMap::new();
+        add_note(parent_msg.clone(), &mut notes, &test_config).await?;
+        add_note(reply_msg.clone(), &mut notes, &test_config).await?;
+
+        // Files are named and fronted the way Denote expects
+        let parent = notes.get("2001").unwrap();
+        let file_name = parent.path.file_name().unwrap().to_str().unwrap().to_string();
+        assert_eq!(file_name, format!("{}--denote-parent.org", parent.id));
+        assert!(crate::denote::is_identifier(&parent.id));
+        let source = fs::read_to_string(&parent.path).await?;
+        assert!(source.starts_with("#+title:      Denote Parent\n#+date:"));
+        assert!(source.contains(&format!("#+identifier: {}", parent.id)));
+        let reply = notes.get("2002").unwrap();
+        assert_ne!(reply.id, parent.id, "Identifiers should be unique");
+        assert!(reply.body.contains(&format!("[[denote:{}][Denote Parent]]", parent.id)));
+
+        // And they load back with the identifier standing in for the ID
+        let paths: Vec<_> = notes.values().map(|n| n.path.clone()).collect();
+        let parent_id = parent.id.clone();
+        let mut reloaded = HashMap::new();
+        load_notes(&mut reloaded, &test_config).await?;
+        assert_eq!(reloaded.get("2001").unwrap().id, parent_id);
+        assert_eq!(reloaded.get("2002").unwrap()._capturebot_parent.as_deref(), Some("2001"));
+
+        // Clean up
+        for path in paths {
+            fs::remove_file(path).await?;
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_inbox_capture() -> Result<(), std::io::Error> {
+        let mut test_config = CapturebotConfig::for_testing("test_inbox_capture");
+        let inbox = test_config.save_dir.join("inbox.org");
+        test_config.capture_target = CaptureTarget::Inbox(inbox.clone());
+        fs::create_dir_all(test_config.save_dir.as_path()).await?;
+        fs::write(&inbox, "#+title: Inbox\n* Existing entry").await?;
+
+        let parent_msg = create_test_message(3001, "Inbox Parent\nparent body\n* not a heading", None);
+        let reply_msg = create_test_message(3002, "Inbox Reply\nreply body", Some(3001));
+        let mut notes = HashMap::new();
+        add_note(parent_msg.clone(), &mut notes, &test_config).await?;
+        add_note(reply_msg.clone(), &mut notes, &test_config).await?;
+
+        // Both captures were appended to the inbox, after what was there
+        let source = fs::read_to_string(&inbox).await?;
+        assert!(source.starts_with("#+title: Inbox\n* Existing entry\n* Inbox Parent\n:PROPERTIES:"));
+        assert!(source.contains("\n * not a heading\n"));
+        let parent_id = notes.get("3001").unwrap().id.clone();
+        assert!(source.contains(&format!("** Related: [[id:{parent_id}][Inbox Parent]]")));
+        assert!(!inbox.with_file_name(".#inbox.org").exists(), "Lock should be released");
+
+        // And they load back as heading notes
+        let mut reloaded = HashMap::new();
+        load_notes(&mut reloaded, &test_config).await?;
+        let parent = reloaded.get("3001").expect("Inbox parent should load");
+        assert_eq!(parent.path, inbox);
+        assert_eq!(parent.outline_path, vec!["Inbox Parent".to_string()]);
+        assert!(read_note_source(parent).await?.contains("parent body"));
+        assert!(reloaded.contains_key("3002"));
+
+        // Clean up
+        fs::remove_file(&inbox).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dailies_capture() -> Result<(), std::io::Error> {
+        let mut test_config = CapturebotConfig::for_testing("test_dailies_capture");
+        let dailies = test_config.save_dir.join("daily");
+        test_config.capture_target = CaptureTarget::Dailies(dailies.clone());
+        test_config.timezone = "Asia/Kolkata".parse().unwrap();
+
+        // Late evening UTC is already the next day in Kolkata
+        let date = DateTime::parse_from_rfc3339("2024-05-06T23:30:00Z").unwrap().to_utc();
+        let mut parent_msg = create_test_message(4001, "Daily Parent\nparent body", None);
+        let mut reply_msg = create_test_message(4002, "Daily Reply\nreply body", Some(4001));
+        let mut other_msg = create_test_message(4003, "Daily Other\nother body", None);
+        parent_msg.date = date;
+        reply_msg.date = date;
+        other_msg.date = date;
+        let mut notes = HashMap::new();
+        add_note(parent_msg, &mut notes, &test_config).await?;
+        add_note(other_msg, &mut notes, &test_config).await?;
+        add_note(reply_msg, &mut notes, &test_config).await?;
+
+        // The daily was created with an ID and title, and the reply went
+        // under its parent rather than at the end
+        let daily = dailies.join("2024-05-07.org");
+        let source = fs::read_to_string(&daily).await?;
+        assert!(source.starts_with(":PROPERTIES:\n:ID: "));
+        assert!(source.contains("#+title: 2024-05-07\n* Daily Parent\n"));
+        let reply_at = source.find("** Daily Reply").expect("Reply should be nested");
+        assert!(reply_at < source.find("* Daily Other").unwrap());
+        assert!(!source.contains("Related"), "Nested replies don't need a link");
+
+        // Entries load back with their capturebot IDs for dedup
+        let mut reloaded = HashMap::new();
+        load_notes(&mut reloaded, &test_config).await?;
+        assert_eq!(
+            reloaded.get("4002").unwrap().outline_path,
+            vec!["Daily Parent".to_string(), "Daily Reply".to_string()]
+        );
+        assert!(reloaded.contains_key("4001") && reloaded.contains_key("4003"));
+
+        // Clean up
+        fs::remove_dir_all(&dailies).await?;
+
+        Ok(())
+    }
+
+    fn set_text(msg: &mut Message, text: &str) {
+        if let MessageKind::Common(common) = &mut msg.kind
+            && let MediaKind::Text(media) = &mut common.media_kind
+        {
+            media.text = text.to_string();
+        }
+    }
+
+    fn in_group(mut msg: Message, chat_id: i64) -> Message {
+        let group = Chat {
+            id: ChatId(chat_id),
+            kind: ChatKind::Public(ChatPublic { title: Some("Team".to_string()), kind: PublicChatKind::Group }),
+        };
+        if let MessageKind::Common(common) = &mut msg.kind
+            && let Some(reply) = &mut common.reply_to_message
+        {
+            reply.chat = group.clone();
+        }
+        msg.chat = group;
+        msg
+    }
+
+    #[tokio::test]
+    async fn test_group_capture() -> Result<(), std::io::Error> {
+        let mut config = CapturebotConfig::for_testing("test_group_capture");
+        let _ = fs::remove_dir_all(&config.save_dir).await;
+        config.chats.push(ChatConfig {
+            chat_id: -100,
+            user_id: config.user_id,
+            save_dir: config.save_dir.join("team"),
+            topics: Vec::new(),
+        });
+        let chat_config = config.for_chat(-100).unwrap();
+        let mut notes = HashMap::new();
+
+        // ordinary group chatter is left alone
+        let chatter = in_group(create_test_message(1, "just chatting", None), -100);
+        assert!(!Message::is_valid_msg(chatter, &config));
+        let elsewhere = in_group(create_test_message(1, "/capture Something", None), -200);
+        assert!(!Message::is_valid_msg(elsewhere, &config));
+        let lookalike = in_group(create_test_message(1, "/captured it", None), -100);
+        assert!(!Message::is_valid_msg(lookalike, &config));
+
+        let command = in_group(create_test_message(2, "/capture@CaptureBot Team Link\nbody", None), -100);
+        assert!(Message::is_valid_msg(command.clone(), &config));
+        add_note(command, &mut notes, &chat_config).await?;
+        let note = &notes["-100:2"];
+        assert_eq!(note.title, "Team Link");
+        assert!(note.path.starts_with(config.save_dir.join("team")));
+        let source = fs::read_to_string(&note.path).await?;
+        assert!(source.contains(":CAPTUREBOT_GROUP: Team"), "{source}");
+        assert!(source.contains(":CAPTUREBOT_AUTHOR: Test User"), "{source}");
+
+        // replies to a /capture are captured as well, and relate to it
+        let mut follow_up = in_group(create_test_message(3, "Follow Up", Some(2)), -100);
+        if let MessageKind::Common(common) = &mut follow_up.kind {
+            set_text(common.reply_to_message.as_mut().unwrap(), "/capture Team Link");
+        }
+        assert!(Message::is_valid_msg(follow_up.clone(), &config));
+        add_note(follow_up, &mut notes, &chat_config).await?;
+        assert_eq!(notes["-100:3"]._capturebot_parent.as_deref(), Some("-100:2"));
+
+        // a bare /capture saves the message it replies to
+        let bare = in_group(create_test_message(5, "/capture", Some(4)), -100);
+        add_note(bare, &mut notes, &chat_config).await?;
+        assert_eq!(notes["-100:4"].title, "Parent message");
+
+        let mut mention = in_group(create_test_message(6, "@capturebot Mentioned", None), -100);
+        if let MessageKind::Common(common) = &mut mention.kind
+            && let MediaKind::Text(media) = &mut common.media_kind
+        {
+            media.entities.push(MessageEntity::new(MessageEntityKind::Mention, 0, 11));
+        }
+        assert!(Message::is_valid_msg(mention.clone(), &config));
+        add_note(mention, &mut notes, &chat_config).await?;
+        assert_eq!(notes["-100:6"].title, "Mentioned");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_forum_topics() -> Result<(), std::io::Error> {
+        let mut config = CapturebotConfig::for_testing("test_forum_topics");
+        let _ = fs::remove_dir_all(&config.save_dir).await;
+        let team_dir = config.save_dir.join("team");
+        config.chats.push(ChatConfig {
+            chat_id: -100,
+            user_id: config.user_id,
+            save_dir: team_dir.clone(),
+            topics: vec![
+                TopicConfig {
+                    thread_id: None,
+                    name: Some("Reading".to_string()),
+                    save_dir: Some(team_dir.join("books")),
+                    tag: Some("reading".to_string()),
+                },
+                TopicConfig {
+                    thread_id: Some(9),
+                    name: None,
+                    save_dir: None,
+                    tag: Some("work".to_string()),
+                },
+            ],
+        });
+
+        // configured topics go where they're told, by name or thread ID
+        let reading = config.for_topic(-100, 12, Some("reading")).unwrap();
+        assert_eq!(reading.save_dir, team_dir.join("books"));
+        assert_eq!(reading.capture_tags, vec!["reading".to_string()]);
+        let work = config.for_topic(-100, 9, Some("Work")).unwrap();
+        assert_eq!(work.save_dir, team_dir);
+        assert_eq!(work.capture_tags, vec!["work".to_string()]);
+
+        // other topics get a directory named after them
+        let mut root = in_group(create_test_message(50, "", None), -100);
+        root.kind = MessageKind::ForumTopicCreated(MessageForumTopicCreated {
+            forum_topic_created: ForumTopicCreated {
+                name: "Recipes".to_string(),
+                icon_color: Rgb { r: 0, g: 0, b: 0 },
+                icon_custom_emoji_id: None,
+            },
+        });
+        let recipes = config.for_topic(-100, 50, Some("Recipes")).unwrap();
+        assert_eq!(recipes.save_dir, team_dir.join("recipes"));
+        assert!(recipes.capture_tags.is_empty());
+
+        let mut notes = HashMap::new();
+        let mut in_topic = in_group(create_test_message(51, "/capture Pancakes", Some(50)), -100);
+        in_topic.is_topic_message = true;
+        in_topic.thread_id = Some(ThreadId(MessageId(50)));
+        if let MessageKind::Common(common) = &mut in_topic.kind {
+            common.reply_to_message = Some(Box::new(root));
+        }
+        assert!(Message::is_valid_msg(in_topic.clone(), &config));
+        assert_eq!(message_topic(&in_topic, &notes), Some((50, Some("Recipes".to_string()))));
+        add_note(in_topic.clone(), &mut notes, &recipes).await?;
+        let note = &notes["-100:51"];
+        assert!(note.path.starts_with(team_dir.join("recipes")));
+        // being in a topic doesn't make it a reply to the topic
+        assert_eq!(note._capturebot_parent, None);
+        let source = fs::read_to_string(&note.path).await?;
+        assert!(source.contains(":CAPTUREBOT_TOPIC: 50 Recipes"), "{source}");
+
+        // the loader picks the topic back up, so later messages that reply
+        // to something else still find its name
+        let mut loaded = HashMap::new();
+        load_notes(&mut loaded, &config).await?;
+        assert_eq!(loaded["-100:51"].topic(), Some((50, "Recipes")));
+        let mut reply = in_topic;
+        reply.id = MessageId(52);
+        if let MessageKind::Common(common) = &mut reply.kind {
+            common.reply_to_message = Some(Box::new(in_group(create_test_message(51, "Pancakes", None), -100)));
+        }
+        assert_eq!(message_topic(&reply, &loaded), Some((50, Some("Recipes".to_string()))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_channel_posts() -> Result<(), std::io::Error> {
+        let mut config = CapturebotConfig::for_testing("test_channel_posts");
+        let _ = fs::remove_dir_all(&config.save_dir).await;
+        config.chats.push(ChatConfig {
+            chat_id: -1001234567890,
+            user_id: config.user_id,
+            save_dir: config.save_dir.clone(),
+            topics: Vec::new(),
+        });
+        let mut post = create_test_message(7, "Link Log\nfirst take", None);
+        post.from = None;
+        post.chat = Chat {
+            id: ChatId(-1001234567890),
+            kind: ChatKind::Public(ChatPublic {
+                title: Some("Links".to_string()),
+                kind: PublicChatKind::Channel(PublicChatChannel { username: Some("linklog".to_string()) }),
+            }),
+        };
+        assert!(Message::is_valid_msg(post.clone(), &config));
+
+        let mut notes = HashMap::new();
+        add_note(post.clone(), &mut notes, &config).await?;
+        let note = notes["-1001234567890:7"].clone();
+        assert!(note.refs.contains(&"https://t.me/linklog/7".to_string()), "{:?}", note.refs);
+        let source = fs::read_to_string(&note.path).await?;
+        assert!(source.contains(":CAPTUREBOT_CHANNEL: Links"), "{source}");
+
+        // edits rewrite the note in place, keeping its ID and whatever was
+        // added to it since
+        tag_note("-1001234567890:7", &["curated".to_string()], &mut notes).await?;
+        todo_note("-1001234567890:7", &mut notes).await?;
+        let source = fs::read_to_string(&note.path).await?;
+        fs::write(&note.path, format!("{source}* From Emacs\nkept\n")).await?;
+        set_text(&mut post, "Link Log Revised\nsecond take");
+        update_note(post.clone(), &mut notes, &config).await?;
+        let edited = &notes["-1001234567890:7"];
+        assert_eq!((&edited.id, &edited.path), (&note.id, &note.path));
+        assert_eq!((edited.title.as_str(), &edited.tags), ("Link Log Revised", &vec!["curated".to_string()]));
+        let source = fs::read_to_string(&note.path).await?;
+        assert!(source.contains("second take") && !source.contains("first take"), "{source}");
+        assert!(source.contains("#+title: Link Log Revised\n#+filetags: :curated:\n"), "{source}");
+        assert!(source.contains(":ROAM_REFS: https://t.me/linklog/7"), "{source}");
+        assert!(source.contains("* TODO Link Log\n* From Emacs\nkept\n"), "{source}");
+        assert_eq!(std::fs::read_dir(&config.save_dir)?.count(), 1);
+
+        // in an inbox, only the edited heading changes
+        config.capture_target = CaptureTarget::Inbox(config.save_dir.join("inbox.org"));
+        post.id = MessageId(8);
+        add_note(post.clone(), &mut notes, &config).await?;
+        let mut reply = create_test_message(9, "Reply", Some(8));
+        reply.chat = post.chat.clone();
+        add_note(reply, &mut notes, &config).await?;
+        tag_note("-1001234567890:8", &["curated".to_string()], &mut notes).await?;
+        todo_note("-1001234567890:8", &mut notes).await?;
+        set_text(&mut post, "Link Log Again\nthird take");
+        update_note(post, &mut notes, &config).await?;
+        let inbox = fs::read_to_string(config.save_dir.join("inbox.org")).await?;
+        assert!(inbox.contains("third take") && !inbox.contains("second take"), "{inbox}");
+        assert!(inbox.contains("* TODO Link Log Again :curated:\n:PROPERTIES:\n"), "{inbox}");
+        assert!(inbox.contains("* Reply"), "{inbox}");
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_reply_relationship() -> Result<(), std::io::Error> {
         // Set up test environment
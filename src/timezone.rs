@@ -0,0 +1,52 @@
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use chrono::{DateTime, FixedOffset, Local, Utc};
+use chrono_tz::Tz;
+
+/// The timezone dates are shown in: an IANA zone such as `Europe/Berlin`, or
+/// whatever the system is set to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Timezone {
+    Local,
+    Iana(Tz),
+}
+
+impl Default for Timezone {
+    fn default() -> Self {
+        Self::Iana(Tz::UTC)
+    }
+}
+
+impl FromStr for Timezone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("local") {
+            Ok(Self::Local)
+        } else {
+            s.parse::<Tz>()
+                .map(Self::Iana)
+                .map_err(|_| format!("unknown timezone {s:?}, expected an IANA name or \"local\""))
+        }
+    }
+}
+
+impl Display for Timezone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Local => f.write_str("local"),
+            Self::Iana(tz) => f.write_str(tz.name()),
+        }
+    }
+}
+
+impl Timezone {
+    /// `date` as a wall-clock time in this timezone.
+    pub fn localize(&self, date: DateTime<Utc>) -> DateTime<FixedOffset> {
+        match self {
+            Self::Local => date.with_timezone(&Local).fixed_offset(),
+            Self::Iana(tz) => date.with_timezone(tz).fixed_offset(),
+        }
+    }
+}
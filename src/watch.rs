@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::{Mutex, mpsc};
+
+use crate::{CapturebotConfig, CapturebotNote, forget_file, fulltext, reload_file};
+
+/// `path` as the loader knows it. notify reports absolute paths, while the
+/// note directories, and so the paths of loaded notes, may be relative.
+fn loaded_path(path: PathBuf, dirs: &[(PathBuf, PathBuf)]) -> PathBuf {
+    dirs.iter()
+        .find_map(|(absolute, dir)| path.strip_prefix(absolute).ok().map(|rest| dir.join(rest)))
+        .unwrap_or(path)
+}
+
+fn is_note_file(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == "org")
+        && path
+            .file_name()
+            .and_then(|f| f.to_str())
+            // emacs lock files
+            .is_some_and(|f| !f.starts_with(".#"))
+}
+
+/// Watches `read_dir` and `save_dir`, keeping `notes` in step with edits,
+/// renames and deletions made outside the bot.
+///
+/// The returned watcher stops watching when dropped, so keep it alive for as
+/// long as the bot runs.
+pub fn watch_notes(
+    notes: Arc<Mutex<HashMap<String, CapturebotNote>>>,
+    config: &CapturebotConfig,
+) -> notify::Result<RecommendedWatcher> {
+    let (tx, mut rx) = mpsc::unbounded_channel::<Event>();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| match event {
+        Ok(event) => {
+            tx.send(event).ok();
+        }
+        Err(e) => log::warn!("note watcher error: {e}"),
+    })?;
+    let mut dirs = vec![config.read_dir.clone(), config.save_dir.clone()];
+    dirs.dedup();
+    for dir in &dirs {
+        watcher.watch(dir, RecursiveMode::Recursive)?;
+    }
+    let dirs = dirs
+        .into_iter()
+        .map(|dir| Ok((std::path::absolute(&dir)?, dir)))
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    let config = config.clone();
+    tokio::spawn(async move {
+        while let Some(event) = rx.recv().await {
+            if matches!(event.kind, EventKind::Access(_)) {
+                continue;
+            }
+            let paths: Vec<PathBuf> = event
+                .paths
+                .into_iter()
+                .filter(|p| is_note_file(p))
+                .map(|p| loaded_path(p, &dirs))
+                .collect();
+            if paths.is_empty() {
+                continue;
+            }
+            let mut notes_guard = notes.lock().await;
+            for path in paths {
+                if path.exists() {
+                    log::info!("reloading {}", path.display());
+                    if let Err(e) = reload_file(&path, &mut notes_guard, &config).await {
+                        log::warn!("couldn't reload {}: {e}", path.display());
+                    }
+                } else {
+                    log::info!("forgetting {}", path.display());
+                    let forgotten: Vec<String> = notes_guard
+                        .values()
+                        .filter(|n| n.path == path)
+                        .map(|n| n.capturebot_id.clone())
+                        .collect();
+                    forget_file(&path, &mut notes_guard);
+                    fulltext::update(&[], &forgotten, &config).await;
+                }
+            }
+        }
+    });
+    Ok(watcher)
+}
+
+/// Watches the config file at `path`, sending on `changes` whenever it's
+/// written. The file's directory is watched rather than the file itself, so
+/// that editors which save by replacing the file don't end the watch.
+pub fn watch_config(
+    path: &Path,
+    changes: mpsc::UnboundedSender<()>,
+) -> notify::Result<RecommendedWatcher> {
+    let name = path.file_name().map(|n| n.to_os_string());
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| match event {
+        Ok(event) => {
+            if !matches!(event.kind, EventKind::Access(_))
+                && event.paths.iter().any(|p| p.file_name() == name.as_deref())
+            {
+                changes.send(()).ok();
+            }
+        }
+        Err(e) => log::warn!("config watcher error: {e}"),
+    })?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    Ok(watcher)
+}
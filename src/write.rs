@@ -0,0 +1,80 @@
+use std::path::{Path, PathBuf};
+
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+use uuidgen::gen_uuid;
+
+// how many suffixed names to try before giving up on a crowded path
+const MAX_SUFFIX: usize = 1000;
+
+/// `path` with `-n` inserted before the extension, or `path` itself for n = 0.
+fn suffixed(path: &Path, n: usize) -> PathBuf {
+    if n == 0 {
+        return path.to_path_buf();
+    }
+    let stem = path.file_stem().map(|s| s.to_string_lossy()).unwrap_or_default();
+    let name = match path.extension() {
+        Some(ext) => format!("{stem}-{n}.{}", ext.to_string_lossy()),
+        None => format!("{stem}-{n}"),
+    };
+    path.with_file_name(name)
+}
+
+/// Writes `contents` to `path` without ever clobbering an existing file or
+/// leaving a half-written one behind.
+///
+/// The contents go to a temporary file in the same directory, which is
+/// fsynced and then hard-linked into place; linking fails rather than
+/// replacing an existing file, in which case a `-1`, `-2`, ... suffix is
+/// tried instead. Missing parent directories are created first. Returns the
+/// path that was actually written.
+pub async fn write_new_file(path: &Path, contents: &str) -> Result<PathBuf, std::io::Error> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    fs::create_dir_all(dir).await?;
+    let tmp_path = dir.join(format!(".capturebot-{}.tmp", gen_uuid(true)));
+    let mut tmp = File::create(&tmp_path).await?;
+    let written = async {
+        tmp.write_all(contents.as_bytes()).await?;
+        tmp.sync_all().await?;
+        for n in 0..MAX_SUFFIX {
+            let target = suffixed(path, n);
+            match fs::hard_link(&tmp_path, &target).await {
+                Ok(()) => return Ok(target),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("no free file name left for {}", path.display()),
+        ))
+    }
+    .await;
+    fs::remove_file(&tmp_path)
+        .await
+        .inspect_err(|e| log::warn!("couldn't remove {}: {e}", tmp_path.display()))
+        .ok();
+    if let Ok(dir) = File::open(dir).await {
+        dir.sync_all().await.ok();
+    }
+    written
+}
+
+/// Atomically replaces the contents of `path`, for edits to files that
+/// already exist. Like `write_new_file`, readers only ever see the old or the
+/// new contents in full.
+pub async fn replace_file(path: &Path, contents: &str) -> Result<(), std::io::Error> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let tmp_path = dir.join(format!(".capturebot-{}.tmp", gen_uuid(true)));
+    let mut tmp = File::create(&tmp_path).await?;
+    let replaced = async {
+        tmp.write_all(contents.as_bytes()).await?;
+        tmp.sync_all().await?;
+        fs::rename(&tmp_path, path).await
+    }
+    .await;
+    if replaced.is_err() {
+        fs::remove_file(&tmp_path).await.ok();
+    }
+    replaced
+}